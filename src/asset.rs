@@ -6,7 +6,17 @@ use thiserror::Error;
 pub const DEAL_EXPIRY_TIME: Uint128 = Uint128::new(21600);  // 6 hours
 pub const DISPUTE_EXPIRY_TIME: Uint128 = Uint128::new(864000); // 10 days
 pub const POST_EXPIRY_TIME: Uint128 = Uint128::new(432000); // 5 days
+pub const MODERATOR_ASSIGNMENT_TIME: Uint128 = Uint128::new(172800); // 2 days for the assigned moderator to vote
 pub const COMMISSION_MULTIPLIER: Uint128 = Uint128::new(10000);
+pub const EMERGENCY_TIMELOCK: Uint128 = Uint128::new(259200);  // 3 days before admins can trigger emergency refunds
+pub const EXPIRE_POSTS_DEFAULT_LIMIT: u32 = 30;  // post ids checked by one ExpirePosts
+pub const EXPIRE_POSTS_MAX_LIMIT: u32 = 100;
+pub const MIGRATE_PAST_DEALS_DEFAULT_LIMIT: u32 = 30;  // 0.1.0 past deals moved by one MigratePastDeals
+pub const MIGRATE_PAST_DEALS_MAX_LIMIT: u32 = 100;
+pub const MAX_CLOSE_REASON_LENGTH: usize = 280;
+pub const MAX_EVIDENCE_HASH_LENGTH: usize = 128;  // hex digest or IPFS CID of the evidence file
+pub const MAX_EVIDENCE_NOTE_LENGTH: usize = 500;
+pub const MAX_EVIDENCE_PER_DEAL: usize = 20;
 
 #[derive(Hash, Eq, Default)]
 #[cw_serde]
@@ -128,7 +138,96 @@ pub enum DealState {
     Resolve,
     CancelAsDealerMissTransfer,
     CancelAsCustomerMissTransfer,
-    CancelAsDispute
+    CancelAsDispute,
+    SettledSplit,
+    AdminClosed,
+    EmergencyRefund
+}
+
+// outcome a moderator votes for on a disputed deal
+#[derive(Eq)]
+#[cw_serde]
+pub enum DisputeDecision {
+    Release,  // pay the crypto buyer, as ResolveDeal
+    Refund,  // give the deposit back, as CancelDeal
+    Split {
+        to_receiver_bps: Uint128,  // share of the crypto buyer, the depositor gets the rest
+    },
+}
+
+// holder of an escrow balance in the contract
+#[derive(Eq)]
+#[cw_serde]
+pub enum EscrowOwner {
+    Post(Uint128),  // dealer deposit not yet taken by a deal
+    Deal(Uint128),  // deposit locked in a deal
+    Revenue,  // commission not yet withdrawn
+}
+
+// circuit breaker levels, each one stops more than the previous
+#[derive(Eq, PartialOrd, Ord)]
+#[cw_serde]
+pub enum ContractStatus {
+    Normal,
+    StopTransactions,  // no new posts or deals, existing deals can complete
+    StopAllButRefunds,  // only refunds and emergency withdraws
+    Frozen,  // nothing but SetStatus
+}
+
+// deposit to reclaim in the StopAllButRefunds status
+#[derive(Eq)]
+#[cw_serde]
+pub enum EmergencyTarget {
+    Post { post_id: Uint128 },
+    Deal { deal_id: Uint128 },
+}
+
+// how an admin force-close settles the crypto deposited in a deal
+#[derive(Eq)]
+#[cw_serde]
+pub enum DealSettlement {
+    RefundDepositor,  // back to the crypto seller who deposited it
+    PayCounterparty,  // to the crypto buyer, as if the deal was resolved
+    Split {
+        counterparty_bps: Uint128,  // share of the counterparty, the depositor gets the rest
+    },
+}
+
+// where the amount a cancelled deal took from its post goes
+#[derive(Eq, Default)]
+#[cw_serde]
+pub enum DealCancelPolicy {
+    #[default]
+    RestoreToPost,  // back to the post if it is still open, otherwise like RefundDealer
+    RefundDealer,  // dealer deposit is refunded, nothing to refund for a dealer buy post
+}
+
+// limits on the active deals of a user, no limit if None
+#[derive(Eq, Default)]
+#[cw_serde]
+pub struct DealLimits {
+    pub max_customer_deals: Option<u32>,  // active deals a user can have entered as customer
+    pub max_dealer_deals: Option<u32>,  // active deals on the posts of a dealer
+    pub customer_cool_down: Uint128,  // seconds a customer cannot enter deals after missing a deadline, 0 to disable
+}
+
+// what a party can do with a dispute the moderators did not settle before its expiry
+#[derive(Eq, Default)]
+#[cw_serde]
+pub enum DisputeTimeoutOutcome {
+    #[default]
+    RefundDepositor,  // as a refund vote reaching the quorum
+    EscalateToAdmins,  // flag the deal for the admins to close with AdminCloseDeal
+}
+
+// role of an address with respect to a deal, an address can hold more than one
+#[derive(Hash, Eq)]
+#[cw_serde]
+pub enum DealRole {
+    Admin,
+    Moderator,
+    Customer,
+    Dealer,
 }
 
 #[cw_serde]
@@ -173,8 +272,29 @@ pub enum ContractError {
     #[error("Unexpected Post State")]
     UnexpectPostState,
 
-    #[error("You enter more than 1 Deal")]
-    ConcurrentDealNotAllowed,
+    #[error("Active deal limit of {0} reached")]
+    ConcurrentDealNotAllowed(u32),
+
+    #[error("Cannot enter a deal before {0}, a previous deal expired without action")]
+    DealCoolDown(Uint128),
+
+    #[error("Evidence content hash must be between 1 and {0} characters, note at most {1} characters")]
+    InvalidEvidence(usize, usize),
+
+    #[error("No more than {0} evidence entries per deal")]
+    TooMuchEvidence(usize),
+
+    #[error("Assigned moderator can vote until {0}")]
+    AssignmentNotExpired(Uint128),
+
+    #[error("Dispute is already escalated to the admins")]
+    AlreadyEscalated,
+
+    #[error("Moderator already voted on this dispute")]
+    AlreadyVoted,
+
+    #[error("Dispute quorum must be at least 1")]
+    InvalidQuorum,
 
     #[error("Deposit required: {0}, but user only deposit {1}")]
     MismatchDepositAmount(Uint128, Uint128),
@@ -191,12 +311,75 @@ pub enum ContractError {
     #[error("Deal expiry datetime: {0}")]
     DealNotExpired(Uint128),
 
+    #[error("Amount cannot be zero")]
+    ZeroAmount,
+
+    #[error("No amount to withdraw")]
+    NothingToWithdraw,
+
+    #[error("Min amount cannot be more than the amount")]
+    MinAmountMoreThanAmount,
+
+    #[error("Settle price cannot be zero")]
+    ZeroSettlePrice,
+
+    #[error("Invalid ISO-4217 currency code {0}")]
+    InvalidCurrencyCode(String),
+
+    #[error("Unsupported currency {0}")]
+    UnsupportedCurrency(String),
+
+    #[error("Dealer cannot enter a deal on their own post")]
+    SelfDealing,
+
     #[error("The amount entered is less than dealer minimum amount requirement")]
     AmountLessThanDealerReq,
 
     #[error("The amount entered is more than the max amount selling in the post")]
     AmountMoreThanPost,
 
+    #[error("The amount entered is more than dealer maximum amount per deal")]
+    AmountMoreThanDealerMax,
+
+    #[error("Max amount must be between the min amount and the post amount")]
+    InvalidMaxAmount,
+
     #[error("Still active deal in this post")]
     ActiveDealExist,
+
+    #[error("Post expired at {0}")]
+    PostExpired(Uint128),
+
+    #[error("Escrow balance {0} is less than {1}")]
+    InsufficientEscrow(Uint128, Uint128),
+
+    #[error("Close reason must be between 1 and {0} characters")]
+    InvalidCloseReason(usize),
+
+    #[error("Split of {0} bps is more than 10000 bps")]
+    InvalidSplit(Uint128),
+
+    #[error("Not allowed while the contract status is {0:?}")]
+    ContractPaused(ContractStatus),
+
+    #[error("Emergency withdraw is only available in the StopAllButRefunds status")]
+    NotInEmergencyMode {},
+
+    #[error("Admin refunds are available after {0}")]
+    EmergencyTimelock(Uint128),
+
+    #[error("Post amount is less than the token minimum: {0}")]
+    PostAmountLessThanTokenMin(Uint128),
+
+    #[error("Post amount is more than the token maximum: {0}")]
+    PostAmountMoreThanTokenMax(Uint128),
+
+    #[error("Cannot migrate from contract {0}")]
+    MismatchContractName(String),
+
+    #[error("Cannot downgrade contract from version {0} to {1}")]
+    CannotDowngrade(String, String),
+
+    #[error("Legacy revenue split adds up to {0}, the stored revenue is {1}")]
+    LegacyRevenueMismatch(Uint128, Uint128),
 }
@@ -1,12 +1,16 @@
 use cosmwasm_std::{
-    entry_point, to_binary, Addr, Binary, Deps, DepsMut, Env, MessageInfo, QueryRequest, Response, StdError, StdResult, Uint128, WasmQuery
+    entry_point, from_binary, to_binary, Addr, Binary, Deps, DepsMut, Env, MessageInfo, QueryRequest, Response, StdError, StdResult, Uint128, WasmQuery
 };
+use secret_toolkit::permit::{validate, Permit};
 use secret_toolkit::snip20::register_receive_msg;
 use secret_toolkit::utils::pad_handle_result;
 
-use crate::asset::{Contract, ContractError};
-use crate::msg::{AuthQueryAnswer, AuthQueryMsg, ExecuteMsg, InstantiateMsg, QueryAnswer, QueryMsg};
-use crate::state::{Config, Deal, PaymentInfo, Post, ACTIVE_DEALS, ACTIVE_POSTS, CONFIG, MAX_DEAL_ID, MAX_POST_ID, MODERATORS, PAST_DEALS, TOKEN_REVENUE, USER_PAYMENT_INFO};
+use crate::asset::{Contract, ContractError, ContractStatus, DepositAction, DisputeDecision};
+use crate::msg::{AuthQueryAnswer, AuthQueryMsg, ExecuteMsg, InstantiateMsg, MigrateMsg, QueryMsg, QueryPermission, QueryWithPermit};
+use crate::migrate::{migrate_state, set_contract_version, CONTRACT_VERSION};
+use crate::execute::validate_deal_token;
+use crate::validation::validate_currency;
+use crate::state::{Config, StatusInfo, CONFIG, CONTRACT_STATUS, CURRENCIES, DEAL_TOKENS, MAX_DEAL_ID, MAX_POST_ID, MODERATORS, PREFIX_REVOKED_PERMITS};
 use crate::{execute, query};
 
 pub const RESPONSE_BLOCK_SIZE: usize = 256;
@@ -25,56 +29,62 @@ pub fn instantiate(
         .map(|s| deps.api.addr_validate(s).unwrap())
         .collect();
     let moderators = admins_addr.clone();
-    let deal_token_a = msg.deal_token_a.into_valid(deps.api)?;
-    let deal_token_b = msg.deal_token_b.into_valid(deps.api)?;
-    let deal_token_c = msg.deal_token_c.into_valid(deps.api)?;
+
+    let dispute_quorum = msg.dispute_quorum.unwrap_or(1);
+    if dispute_quorum == 0 {
+        return Err(StdError::generic_err(ContractError::InvalidQuorum.to_string()));
+    }
 
     CONFIG.save(
         deps.storage,
         &Config {
             admins: admins_addr,
             deal_commission: msg.deal_commission.clone(),
-            deal_token_a: deal_token_a.clone(),
-            deal_token_b: deal_token_b.clone(),
-            deal_token_c: deal_token_c.clone(),
             query_auth: msg.query_auth.into_valid(deps.api)?,
             governance: None,
+            treasury: msg.treasury.map(|x| deps.api.addr_validate(&x)).transpose()?,
+            deal_cancel_policy: msg.deal_cancel_policy.unwrap_or_default(),
+            deal_limits: msg.deal_limits.unwrap_or_default(),
+            dispute_quorum,
+            dispute_timeout_outcome: msg.dispute_timeout_outcome.unwrap_or_default(),
         }
     )?;
 
+    set_contract_version(deps.storage)?;
+
+    CONTRACT_STATUS.save(deps.storage, &StatusInfo {
+        status: ContractStatus::Normal,
+        since: Uint128::new(env.block.time.seconds() as u128),
+    })?;
+
     MAX_DEAL_ID.save(deps.storage, &Uint128::zero())?;
     MAX_POST_ID.save(deps.storage, &Uint128::zero())?;
-    TOKEN_REVENUE.save(deps.storage, &Uint128::zero())?;
-    PAST_DEALS.save(deps.storage, &vec![])?;
-    ACTIVE_POSTS.save(deps.storage, &vec![])?;
-    ACTIVE_DEALS.save(deps.storage, &vec![])?;
     // initially, moderators is admins
     MODERATORS.save(deps.storage, &moderators)?;
 
+    for currency in msg.currencies {
+        let currency = validate_currency(currency).map_err(|e| StdError::generic_err(e.to_string()))?;
+        CURRENCIES.insert(deps.storage, &currency.code, &currency)?;
+    }
+
+    // whitelist the deal tokens and register this contract to receive them
+    let mut messages = vec![];
+    for token in msg.deal_tokens {
+        let deal_token = validate_deal_token(deps.api, token)?;
+
+        messages.push(register_receive_msg(
+            env.contract.code_hash.clone(),
+            None,
+            RESPONSE_BLOCK_SIZE,
+            deal_token.contract.code_hash.clone(),
+            deal_token.contract.address.clone().into_string(),
+        )?);
+
+        DEAL_TOKENS.insert(deps.storage, &deal_token.contract.address, &deal_token)?;
+    }
+
     let response = Response::new()
-        .add_messages(vec![
-            register_receive_msg(
-                env.contract.code_hash.clone(),
-                None,
-                RESPONSE_BLOCK_SIZE,
-                deal_token_a.code_hash.clone(),
-                deal_token_a.address.into_string().clone(),
-            )?,
-            register_receive_msg(
-                env.contract.code_hash.clone(),
-                None,
-                RESPONSE_BLOCK_SIZE,
-                deal_token_b.code_hash.clone(),
-                deal_token_b.address.into_string().clone(),
-            )?,
-            register_receive_msg(
-                env.contract.code_hash.clone(),
-                None,
-                RESPONSE_BLOCK_SIZE,
-                deal_token_c.code_hash.clone(),
-                deal_token_c.address.into_string().clone(),
-            )?
-        ])
+        .add_messages(messages)
         .add_attribute("status", "success");
 
     Ok(response)
@@ -83,13 +93,23 @@ pub fn instantiate(
 #[entry_point]
 pub fn execute(deps: DepsMut, env: Env, info: MessageInfo, msg: ExecuteMsg
 ) -> Result<Response, ContractError> {
+    let status = CONTRACT_STATUS.load(deps.storage)?.status;
+    if status > max_status(&msg) {
+        return Err(ContractError::ContractPaused(status));
+    }
+
     pad_handle_result(
         match msg {
         ExecuteMsg::UpdateConfig {
             admins,
             deal_commission,
             query_auth,
-            governance
+            governance,
+            treasury,
+            deal_cancel_policy,
+            deal_limits,
+            dispute_quorum,
+            dispute_timeout_outcome
         } => execute::update_config(
             deps,
             env,
@@ -97,10 +117,18 @@ pub fn execute(deps: DepsMut, env: Env, info: MessageInfo, msg: ExecuteMsg
             admins,
             deal_commission,
             query_auth,
-            governance
+            governance,
+            treasury,
+            deal_cancel_policy,
+            deal_limits,
+            dispute_quorum,
+            dispute_timeout_outcome
         ),
-        ExecuteMsg::UpdateDealToken { deal_token_a, deal_token_b, deal_token_c } => {
-            execute::update_deal_token(deps, env, info, deal_token_a, deal_token_b, deal_token_c)
+        ExecuteMsg::AddDealToken { token } => {
+            execute::add_deal_token(deps, env, info, token)
+        },
+        ExecuteMsg::RemoveDealToken { token } => {
+            execute::remove_deal_token(deps, env, info, token)
         },
         ExecuteMsg::AddModerator { mod_addr } => {
             execute::add_moderator(deps, env, info, mod_addr)
@@ -108,6 +136,9 @@ pub fn execute(deps: DepsMut, env: Env, info: MessageInfo, msg: ExecuteMsg
         ExecuteMsg::RemoveModerator { mod_addr } => {
             execute::remove_moderator(deps, env, info, mod_addr)
         },
+        ExecuteMsg::SetCurrency { currency } => {
+            execute::set_currency(deps, env, info, currency)
+        },
         ExecuteMsg::RegisterPaymentInfo { method, detail } => {
             execute::register_user_payment_info(deps, env, info, method, detail)
         },
@@ -123,10 +154,23 @@ pub fn execute(deps: DepsMut, env: Env, info: MessageInfo, msg: ExecuteMsg
             deal_token,
             amount,
             min_amount,
+            max_amount,
             settle_currency,
             settle_price
-        } => execute::add_post(deps, env, info, is_dealer_buy, deal_token, amount, min_amount, settle_currency, settle_price),
+        } => execute::add_post(deps, env, info, is_dealer_buy, deal_token, amount, min_amount, max_amount, settle_currency, settle_price),
         ExecuteMsg::CancelPost { post_id } => execute::cancel_post(deps, env, info, post_id),
+        ExecuteMsg::UpdatePost {
+            post_id,
+            settle_price,
+            min_amount,
+            max_amount,
+            clear_max_amount,
+            amount
+        } => execute::update_post(deps, env, info, post_id, settle_price, min_amount, max_amount, clear_max_amount, amount),
+        ExecuteMsg::RenewPost { post_id } => execute::renew_post(deps, env, info, post_id),
+        ExecuteMsg::WithdrawFromPost { post_id, amount } => execute::withdraw_from_post(deps, env, info, post_id, amount),
+        ExecuteMsg::ExpirePosts { start_after, limit } => execute::expire_posts(deps, env, info, start_after, limit),
+        ExecuteMsg::MigratePastDeals { limit } => execute::migrate_past_deals(deps, env, info, limit),
         ExecuteMsg::EnterDeal { 
             post_id,
             amount
@@ -137,147 +181,241 @@ pub fn execute(deps: DepsMut, env: Env, info: MessageInfo, msg: ExecuteMsg
         ExecuteMsg::DisputeDeal { deal_id } => execute::dispute_deal(deps, env, info, deal_id),
         ExecuteMsg::ResolveDeal { deal_id } => execute::resolve_deal(deps, env, info, deal_id),
         ExecuteMsg::CancelDeal { deal_id } => execute::cancel_deal(deps, env, info, deal_id),
-        ExecuteMsg::AdminDeleteDeal { deal_id } => execute::admin_delete_deal(deps, env, info, deal_id),
-        ExecuteMsg::GetCommission {} => execute::get_commission(deps, env, info)
+        ExecuteMsg::SubmitEvidence {
+            deal_id,
+            content_hash,
+            note
+        } => execute::submit_evidence(deps, env, info, deal_id, content_hash, note),
+        ExecuteMsg::ReassignDispute { deal_id } => execute::reassign_dispute(deps, env, info, deal_id),
+        ExecuteMsg::SettleDispute {
+            deal_id,
+            to_receiver_bps
+        } => execute::cast_dispute_vote(deps, env, info, deal_id, DisputeDecision::Split { to_receiver_bps }),
+        ExecuteMsg::ExpireDispute { deal_id } => execute::expire_dispute(deps, env, info, deal_id),
+        ExecuteMsg::CastDisputeVote { deal_id, decision } => execute::cast_dispute_vote(deps, env, info, deal_id, decision),
+        ExecuteMsg::AdminCloseDeal {
+            deal_id,
+            settlement,
+            reason
+        } => execute::admin_close_deal(deps, env, info, deal_id, settlement, reason),
+        ExecuteMsg::GetCommission { token } => execute::get_commission(deps, env, info, token),
+        ExecuteMsg::RevokePermit { permit_name } => execute::revoke_permit(deps, env, info, permit_name),
+        ExecuteMsg::SetTokenViewingKey { token, key } => execute::set_token_viewing_key(deps, env, info, token, key),
+        ExecuteMsg::SetStatus { status } => execute::set_status(deps, env, info, status),
+        ExecuteMsg::EmergencyWithdraw { target } => execute::emergency_withdraw(deps, env, info, target)
     },
     RESPONSE_BLOCK_SIZE)
 }
 
+// The highest contract status a message still runs in.
+// No wildcard on purpose, every new message has to be classified here.
+fn max_status(msg: &ExecuteMsg) -> ContractStatus {
+    match msg {
+        ExecuteMsg::SetStatus { .. } => ContractStatus::Frozen,
+        // a dealer deposit opens or tops up a post, so it counts as a new post
+        ExecuteMsg::Receive { msg: Some(msg), .. }
+            if matches!(from_binary(msg), Ok(DepositAction::Dealer { .. })) => ContractStatus::Normal,
+        // configuration, no funds moved
+        ExecuteMsg::UpdateConfig { .. }
+        | ExecuteMsg::AddDealToken { .. }
+        | ExecuteMsg::RemoveDealToken { .. }
+        | ExecuteMsg::AddModerator { .. }
+        | ExecuteMsg::RemoveModerator { .. }
+        | ExecuteMsg::SetCurrency { .. }
+        | ExecuteMsg::RevokePermit { .. }
+        | ExecuteMsg::MigratePastDeals { .. }
+        | ExecuteMsg::SetTokenViewingKey { .. } => ContractStatus::StopAllButRefunds,
+        // refunds
+        ExecuteMsg::CancelPost { .. }
+        | ExecuteMsg::WithdrawFromPost { .. }
+        | ExecuteMsg::ExpirePosts { .. }
+        | ExecuteMsg::CancelDeal { .. }
+        | ExecuteMsg::CastDisputeVote { decision: DisputeDecision::Refund, .. }
+        | ExecuteMsg::ExpireDispute { .. }
+        | ExecuteMsg::EmergencyWithdraw { .. } => ContractStatus::StopAllButRefunds,
+        // existing posts and deals
+        ExecuteMsg::RegisterPaymentInfo { .. }
+        | ExecuteMsg::Receive { .. }
+        | ExecuteMsg::ConfirmBankTransfer { .. }
+        | ExecuteMsg::DisputeDeal { .. }
+        | ExecuteMsg::SubmitEvidence { .. }
+        | ExecuteMsg::ReassignDispute { .. }
+        | ExecuteMsg::ResolveDeal { .. }
+        | ExecuteMsg::CastDisputeVote { decision: DisputeDecision::Release, .. }
+        | ExecuteMsg::CastDisputeVote { decision: DisputeDecision::Split { .. }, .. }
+        | ExecuteMsg::SettleDispute { .. }
+        | ExecuteMsg::AdminCloseDeal { .. }
+        | ExecuteMsg::GetCommission { .. } => ContractStatus::StopTransactions,
+        // new posts and deals
+        ExecuteMsg::AddPost { .. }
+        | ExecuteMsg::UpdatePost { .. }
+        | ExecuteMsg::RenewPost { .. }
+        | ExecuteMsg::EnterDeal { .. } => ContractStatus::Normal,
+    }
+}
+
 #[entry_point]
-pub fn query(deps: Deps, _env: Env, msg: QueryMsg) -> StdResult<Binary> {
+pub fn query(deps: Deps, env: Env, msg: QueryMsg) -> StdResult<Binary> {
     match msg {
         QueryMsg::Config{} => to_binary(&query::config(deps)?),
-        QueryMsg::PastDeals {} => to_binary(&query::past_deals(deps)?),
-        QueryMsg::ActiveDeals {} => to_binary(&query::active_deals(deps)?),
-        QueryMsg::ActivePosts {} => to_binary(&query::active_posts(deps)?),
-        QueryMsg::Revenue {} => to_binary(&query::revenue(deps)?),
+        QueryMsg::PastDeals {
+            filter,
+            start_after,
+            limit,
+            order
+        } => to_binary(&query::past_deals(deps, filter, start_after, limit, order)?),
+        QueryMsg::ActiveDeals {
+            filter,
+            start_after,
+            limit,
+            order
+        } => to_binary(&query::active_deals(deps, filter, start_after, limit, order)?),
+        QueryMsg::ActivePosts {
+            filter,
+            start_after,
+            limit,
+            order
+        } => to_binary(&query::active_posts(deps, env, filter, start_after, limit, order)?),
+        QueryMsg::Revenue { token } => to_binary(&query::revenue(deps, token)?),
         QueryMsg::Moderators {} => to_binary(&query::moderators(deps)?),
+        QueryMsg::DealTokens {} => to_binary(&query::deal_tokens(deps)?),
+        QueryMsg::Currencies {} => to_binary(&query::currencies(deps)?),
+        QueryMsg::Solvency {} => to_binary(&query::solvency(deps, env)?),
+        QueryMsg::Status {} => to_binary(&query::status(deps)?),
         QueryMsg::MyPosts {
             key,
             address
         } => {
-            let config = CONFIG.load(deps.storage)?;
-            let is_valid = authenticate(deps, key, address.clone(), config.query_auth)?;
-
-            if !is_valid {
-                return Err(StdError::generic_err("Viewing key not match"));
-            }
-
-            let user = deps.api.addr_validate(&address)?;
-
-            let active_posts = ACTIVE_POSTS.load(deps.storage)?;
-
-            let mut list_posts: Vec<Post> = Vec::new();
-            
-            for post in active_posts {
-                if user == post.dealer.clone() {
-                    list_posts.push(post.clone());
-                }
-            }
-
-            to_binary(&list_posts)
+            let user = authenticated_user(deps, key, address)?;
+            to_binary(&query::my_posts(deps, user)?)
         },
         QueryMsg::MyDeals {
             key,
             address
         } => {
-            let config = CONFIG.load(deps.storage)?;
-            let is_valid = authenticate(deps, key, address.clone(), config.query_auth)?;
-
-            if !is_valid {
-                return Err(StdError::generic_err("Viewing key not match"));
-            }
-
-            let user = deps.api.addr_validate(&address)?;
-
-            let active_deals = ACTIVE_DEALS.load(deps.storage)?;
-
-            let mut list_deals: Vec<Deal> = Vec::new();
-            
-            for deal in active_deals {
-                let customer = deal.customer.clone();
-                if user == customer || user == deal.dealer.clone() {
-                    list_deals.push(deal.clone());
-                }
-            }
-
-            to_binary(&list_deals)
+            let user = authenticated_user(deps, key, address)?;
+            to_binary(&query::my_deals(deps, user)?)
+        },
+        QueryMsg::MyAssignedDisputes {
+            key,
+            address,
+            start_after,
+            limit,
+            order
+        } => {
+            let user = authenticated_user(deps, key, address)?;
+            to_binary(&query::my_assigned_disputes(deps, user, start_after, limit, order)?)
         },
         QueryMsg::MyPaymentInfo {
             key,
             address
         } => {
-            let config = CONFIG.load(deps.storage)?;
-            let is_valid = authenticate(deps, key, address.clone(), config.query_auth)?;
+            let user = authenticated_user(deps, key, address)?;
+            to_binary(&query::my_payment_info(deps, user)?)
+        },
+        QueryMsg::MyDealAllowance {
+            key,
+            address
+        } => {
+            let user = authenticated_user(deps, key, address)?;
+            to_binary(&query::my_deal_allowance(deps, env, user)?)
+        },
+        QueryMsg::DealDetail { key, address, deal_id } => {
+            let user = authenticated_user(deps, key, address)?;
+            to_binary(&query::deal_detail(deps, user, deal_id)?)
+        },
+        QueryMsg::DisputeEvidence { key, address, deal_id } => {
+            let user = authenticated_user(deps, key, address)?;
+            to_binary(&query::dispute_evidence(deps, user, deal_id)?)
+        },
+        QueryMsg::PrivateDeals {
+            key,
+            address,
+            archived,
+            filter,
+            start_after,
+            limit,
+            order
+        } => {
+            let user = authenticated_user(deps, key, address)?;
+            to_binary(&query::private_deals(deps, user, archived, filter, start_after, limit, order)?)
+        },
+        QueryMsg::WithPermit { permit, query } => permit_queries(deps, env, permit, query),
+    }
+}
 
-            if !is_valid {
-                return Err(StdError::generic_err("Viewing key not match"));
-            }
+fn permit_queries(
+    deps: Deps,
+    env: Env,
+    permit: Permit<QueryPermission>,
+    query: QueryWithPermit,
+) -> StdResult<Binary> {
+    // check the signature, the allowed tokens and whether the permit was revoked
+    let account = validate(
+        deps,
+        PREFIX_REVOKED_PERMITS,
+        &permit,
+        env.contract.address.to_string(),
+        None
+    )?;
+    let user = deps.api.addr_validate(&account)?;
 
-            let user = deps.api.addr_validate(&address)?;
+    let required = match query {
+        QueryWithPermit::MyPaymentInfo {} => QueryPermission::PaymentInfo,
+        _ => QueryPermission::Owner,
+    };
 
-            let user_payment_info = USER_PAYMENT_INFO.get(deps.storage, &user);
+    if !permit.check_permission(&required) {
+        return Err(StdError::generic_err(format!(
+            "Permit does not grant the {:?} permission, got {:?}",
+            required,
+            permit.params.permissions
+        )));
+    }
 
-            if let Some(user_payment_info) = user_payment_info {
-                to_binary(&user_payment_info)
-            } else {
-                Err(StdError::generic_err("No payment info is found"))
-            }
-        },
-        QueryMsg::DealDetail { key, address, deal_id  } => {
-            let config = CONFIG.load(deps.storage)?;
-            let curr_admins = config.admins.clone();
-            let curr_mods = MODERATORS.load(deps.storage)?;
-            let active_deals = ACTIVE_DEALS.load(deps.storage)?;
-
-            let is_valid = authenticate(deps, key, address.clone(), config.query_auth)?;
-
-            if !is_valid {
-                return Err(StdError::generic_err("Viewing key not match"));
-            }
-
-            let user = deps.api.addr_validate(&address)?;
-
-            let mut selected_deal: Option<Deal> = None;
-            let mut selected_payment_info: Option<PaymentInfo> = None;
-
-            for deal in active_deals {
-                if deal.deal_id == deal_id {
-                    selected_deal = Some(deal);
-                }
-            }
-
-            if selected_deal.is_none() {
-                return Err(StdError::generic_err("No deal is found"));
-            }
-
-            let selected_deal_unwrap = selected_deal.unwrap();
-
-            let deal_customer = selected_deal_unwrap.customer.clone();
-
-            let permit = !curr_admins.contains(&user.clone())
-                || !curr_mods.contains(&user.clone())
-                || deal_customer.clone() == user.clone()
-                || selected_deal_unwrap.dealer.clone() == user.clone();
-
-            if permit {
-                if selected_deal_unwrap.is_dealer_buy {
-                    selected_payment_info = USER_PAYMENT_INFO.get(deps.storage, &deal_customer.clone());
-                } else {
-                    selected_payment_info = USER_PAYMENT_INFO.get(deps.storage, &selected_deal_unwrap.dealer.clone());
-                }
-            }
-
-            if let Some(selected_payment_info) = selected_payment_info {
-                to_binary(&QueryAnswer::DealDetail {
-                    deal: selected_deal_unwrap,
-                    payment_info: selected_payment_info
-                })
-            } else {
-                Err(StdError::generic_err("No payment info is found"))
-            }
-        }, 
+    match query {
+        QueryWithPermit::MyPosts {} => to_binary(&query::my_posts(deps, user)?),
+        QueryWithPermit::MyDeals {} => to_binary(&query::my_deals(deps, user)?),
+        QueryWithPermit::MyAssignedDisputes {
+            start_after,
+            limit,
+            order
+        } => to_binary(&query::my_assigned_disputes(deps, user, start_after, limit, order)?),
+        QueryWithPermit::MyPaymentInfo {} => to_binary(&query::my_payment_info(deps, user)?),
+        QueryWithPermit::MyDealAllowance {} => to_binary(&query::my_deal_allowance(deps, env, user)?),
+        QueryWithPermit::DealDetail { deal_id } => to_binary(&query::deal_detail(deps, user, deal_id)?),
+        QueryWithPermit::DisputeEvidence { deal_id } => to_binary(&query::dispute_evidence(deps, user, deal_id)?),
+        QueryWithPermit::PrivateDeals {
+            archived,
+            filter,
+            start_after,
+            limit,
+            order
+        } => to_binary(&query::private_deals(deps, user, archived, filter, start_after, limit, order)?),
+    }
+}
+
+// validate the viewing key against query_auth and return the user address
+fn authenticated_user(deps: Deps, key: String, address: String) -> StdResult<Addr> {
+    let config = CONFIG.load(deps.storage)?;
+    let is_valid = authenticate(deps, key, address.clone(), config.query_auth)?;
+
+    if !is_valid {
+        return Err(StdError::generic_err("Viewing key not match"));
     }
+
+    deps.api.addr_validate(&address)
+}
+
+#[entry_point]
+pub fn migrate(deps: DepsMut, _env: Env, msg: MigrateMsg) -> Result<Response, ContractError> {
+    // run the state transforms from the stored version up to this code version
+    let previous = migrate_state(deps.storage, &msg)?;
+
+    Ok(Response::new()
+        .add_attribute("status", "success")
+        .add_attribute("previous_version", previous.version)
+        .add_attribute("version", CONTRACT_VERSION))
 }
 
 pub fn authenticate(deps: Deps, key: String, address: String, query_auth: Contract) -> StdResult<bool> {
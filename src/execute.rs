@@ -1,12 +1,15 @@
 use cosmwasm_std::{
-    from_binary, to_binary, Addr, Binary, CosmosMsg, DepsMut, Env, MessageInfo, Response, StdError, Uint128
+    from_binary, to_binary, Addr, Api, Binary, CosmosMsg, DepsMut, Env, Event, MessageInfo, Response, StdError, StdResult, Storage, Uint128
 };
-use secret_toolkit::snip20::transfer_msg;
+use secret_toolkit::permit::RevokedPermits;
+use secret_toolkit::snip20::{register_receive_msg, set_viewing_key_msg, transfer_msg};
 
-use crate::asset::{ContractError, DealState, DepositAction, PostState, RawContract, COMMISSION_MULTIPLIER, DEAL_EXPIRY_TIME, DISPUTE_EXPIRY_TIME, POST_EXPIRY_TIME};
+use crate::asset::{Contract, ContractError, ContractStatus, DealCancelPolicy, DealLimits, DealSettlement, DealState, DepositAction, DisputeDecision, DisputeTimeoutOutcome, EmergencyTarget, EscrowOwner, PostState, RawContract, COMMISSION_MULTIPLIER, DEAL_EXPIRY_TIME, DISPUTE_EXPIRY_TIME, EMERGENCY_TIMELOCK, EXPIRE_POSTS_DEFAULT_LIMIT, EXPIRE_POSTS_MAX_LIMIT, MAX_CLOSE_REASON_LENGTH, MAX_EVIDENCE_PER_DEAL, MIGRATE_PAST_DEALS_DEFAULT_LIMIT, MIGRATE_PAST_DEALS_MAX_LIMIT, MODERATOR_ASSIGNMENT_TIME, POST_EXPIRY_TIME};
 use crate::contract::RESPONSE_BLOCK_SIZE;
-use crate::msg::{ExecuteAnswer, ResponseStatus};
-use crate::state::{Deal, PaymentInfo, Post, ACTIVE_DEALS, ACTIVE_POSTS, CONFIG, MAX_DEAL_ID, MAX_POST_ID, MODERATORS, PAST_DEALS, TOKEN_REVENUE, USER_PAYMENT_INFO};
+use crate::migrate;
+use crate::validation::{normalize_currency, validate_currency, validate_deal_amount, validate_evidence, validate_not_self_deal, validate_post_amounts, validate_settle_currency, validate_settle_price};
+use crate::msg::{DealTokenMsg, ExecuteAnswer, ResponseStatus};
+use crate::state::{add_revenue, archive_deal, escrow_credit, escrow_debit, escrow_move, load_active_deal, load_active_deal_count, load_active_post, remove_post, save_active_deal, save_active_post, Config, Currency, Deal, DealToken, DisputeVote, Evidence, PaymentInfo, Post, StatusInfo, ACTIVE_POST_IDS, CONFIG, CONTRACT_STATUS, CURRENCIES, DEAL_EVIDENCE, DEAL_TOKENS, MAX_DEAL_ID, MAX_POST_ID, MODERATORS, MODERATOR_CURSOR, PREFIX_REVOKED_PERMITS, TOKEN_REVENUE, TOKEN_VIEWING_KEYS, USER_COOL_DOWN, USER_PAYMENT_INFO};
 
 pub fn update_config(
     deps: DepsMut,
@@ -15,7 +18,12 @@ pub fn update_config(
     admins: Option<Vec<String>>,
     deal_commission: Option<Uint128>,
     query_auth: Option<RawContract>,
-    governance: Option<RawContract>
+    governance: Option<RawContract>,
+    treasury: Option<String>,
+    deal_cancel_policy: Option<DealCancelPolicy>,
+    deal_limits: Option<DealLimits>,
+    dispute_quorum: Option<u32>,
+    dispute_timeout_outcome: Option<DisputeTimeoutOutcome>,
 ) -> Result<Response, ContractError> {
     let mut config = CONFIG.load(deps.storage)?;
 
@@ -45,6 +53,29 @@ pub fn update_config(
         config.governance = Some(governance.into_valid(deps.api)?);
     }
 
+    if let Some(treasury) = treasury {
+        config.treasury = Some(deps.api.addr_validate(&treasury)?);
+    }
+
+    if let Some(deal_cancel_policy) = deal_cancel_policy {
+        config.deal_cancel_policy = deal_cancel_policy;
+    }
+
+    if let Some(deal_limits) = deal_limits {
+        config.deal_limits = deal_limits;
+    }
+
+    if let Some(dispute_quorum) = dispute_quorum {
+        if dispute_quorum == 0 {
+            return Err(ContractError::InvalidQuorum);
+        }
+        config.dispute_quorum = dispute_quorum;
+    }
+
+    if let Some(dispute_timeout_outcome) = dispute_timeout_outcome {
+        config.dispute_timeout_outcome = dispute_timeout_outcome;
+    }
+
     CONFIG.save(deps.storage, &config)?;
 
     Ok(
@@ -54,15 +85,57 @@ pub fn update_config(
     )
 }
 
-pub fn update_deal_token(
+pub fn add_deal_token(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    token: DealTokenMsg,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+
+    // Authorization
+    if let Some(governance) = &config.governance {
+        if info.sender != governance.address {
+            return Err(ContractError::NotGovernanceSender {});
+        }
+    }
+
+    // If governance doesn't exist, we will check admin
+    let curr_admins = &config.admins;
+    if !curr_admins.contains(&info.sender.clone())  {
+        return Err(ContractError::Unauthorized {});
+    }
+
+    let deal_token = validate_deal_token(deps.api, token)?;
+
+    DEAL_TOKENS.insert(deps.storage, &deal_token.contract.address, &deal_token)?;
+
+    // register this contract to receive the token
+    let cosmos_msg = register_receive_msg(
+        env.contract.code_hash,
+        None,
+        RESPONSE_BLOCK_SIZE,
+        deal_token.contract.code_hash,
+        deal_token.contract.address.into_string(),
+    )?;
+
+    Ok(
+        Response::new()
+            .add_message(cosmos_msg)
+            .set_data(to_binary(&ExecuteAnswer::AddDealToken {
+                status: ResponseStatus::Success,
+            })?),
+    )
+}
+
+// Removing a token only stops new posts in it, open posts and deals settle as usual
+pub fn remove_deal_token(
     deps: DepsMut,
     _env: Env,
     info: MessageInfo,
-    deal_token_a: RawContract,
-    deal_token_b: RawContract,
-    deal_token_c: RawContract,
+    token: String,
 ) -> Result<Response, ContractError> {
-    let mut config = CONFIG.load(deps.storage)?;
+    let config = CONFIG.load(deps.storage)?;
 
     // Authorization
     if let Some(governance) = &config.governance {
@@ -77,19 +150,72 @@ pub fn update_deal_token(
         return Err(ContractError::Unauthorized {});
     }
 
-    config.deal_token_a = deal_token_a.into_valid(deps.api)?;
-    config.deal_token_b = deal_token_b.into_valid(deps.api)?;
-    config.deal_token_c = deal_token_c.into_valid(deps.api)?;
+    let token_addr = deps.api.addr_validate(&token)?;
 
-    CONFIG.save(deps.storage, &config)?;
+    if !DEAL_TOKENS.contains(deps.storage, &token_addr) {
+        return Err(ContractError::InvalidDealToken {});
+    }
+
+    DEAL_TOKENS.remove(deps.storage, &token_addr)?;
 
     Ok(
-        Response::new().set_data(to_binary(&ExecuteAnswer::UpdateDealToken {
+        Response::new().set_data(to_binary(&ExecuteAnswer::RemoveDealToken {
             status: ResponseStatus::Success,
         })?),
     )
 }
 
+pub fn validate_deal_token(api: &dyn Api, token: DealTokenMsg) -> StdResult<DealToken> {
+    if let Some(max_post_amount) = token.max_post_amount {
+        if max_post_amount < token.min_post_amount {
+            return Err(StdError::generic_err("Max post amount cannot be lower than min post amount"));
+        }
+    }
+
+    Ok(DealToken {
+        contract: token.token.into_valid(api)?,
+        symbol: token.symbol,
+        decimals: token.decimals,
+        min_post_amount: token.min_post_amount,
+        max_post_amount: token.max_post_amount,
+    })
+}
+
+
+// viewing key of this contract on a token, used by the Solvency query to read the token balance
+pub fn set_token_viewing_key(
+    deps: DepsMut,
+    _env: Env,
+    info: MessageInfo,
+    token: RawContract,
+    key: String,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+
+    if !config.admins.contains(&info.sender.clone()) {
+        return Err(ContractError::Unauthorized {});
+    }
+
+    let token = token.into_valid(deps.api)?;
+
+    TOKEN_VIEWING_KEYS.insert(deps.storage, &token.address, &key)?;
+
+    let cosmos_msg = set_viewing_key_msg(
+        key,
+        None,
+        RESPONSE_BLOCK_SIZE,
+        token.code_hash,
+        token.address.into_string(),
+    )?;
+
+    Ok(
+        Response::new()
+            .add_message(cosmos_msg)
+            .set_data(to_binary(&ExecuteAnswer::SetTokenViewingKey {
+                status: ResponseStatus::Success,
+            })?),
+    )
+}
 
 pub fn add_moderator(
     deps: DepsMut,
@@ -127,6 +253,40 @@ pub fn add_moderator(
 }
 
 
+// add a currency to the registry, or update it
+// disabling a currency only stops new posts, existing posts and deals are not affected
+pub fn set_currency(
+    deps: DepsMut,
+    _env: Env,
+    info: MessageInfo,
+    currency: Currency,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+
+    // Authorization
+    if let Some(governance) = &config.governance {
+        if info.sender != governance.address {
+            return Err(ContractError::NotGovernanceSender {});
+        }
+    }
+
+    // If governance doesn't exist, we will check admin
+    let curr_admins = &config.admins;
+    if !curr_admins.contains(&info.sender.clone())  {
+        return Err(ContractError::Unauthorized {});
+    }
+
+    let currency = validate_currency(currency)?;
+
+    CURRENCIES.insert(deps.storage, &currency.code, &currency)?;
+
+    Ok(
+        Response::new().set_data(to_binary(&ExecuteAnswer::SetCurrency {
+            status: ResponseStatus::Success,
+        })?),
+    )
+}
+
 pub fn remove_moderator(
     deps: DepsMut,
     _env: Env,
@@ -183,6 +343,26 @@ pub fn register_user_payment_info(
     )
 }
 
+pub fn revoke_permit(
+    deps: DepsMut,
+    _env: Env,
+    info: MessageInfo,
+    permit_name: String
+) -> Result<Response, ContractError> {
+    RevokedPermits::revoke_permit(
+        deps.storage,
+        PREFIX_REVOKED_PERMITS,
+        info.sender.as_str(),
+        &permit_name
+    );
+
+    Ok(
+        Response::new().set_data(to_binary(&ExecuteAnswer::RevokePermit {
+            status: ResponseStatus::Success,
+        })?),
+    )
+}
+
 pub fn receive(
     deps: DepsMut,
     env: Env,
@@ -199,93 +379,101 @@ pub fn receive(
     match msg {
         Some(m) => match from_binary(&m)? {
             DepositAction::Customer { deal_id } => {
-                let mut active_deals = ACTIVE_DEALS.load(deps.storage)?;
+                let mut deal = load_active_deal(deps.storage, &deal_id)?;
 
-                match active_deals.iter_mut().find(|x| x.deal_id == deal_id) {
-                    Some(deal) => {
-                        // check deal state is correct
-                        if deal.state != DealState::PendCustomerDeposit {
-                            return Err(ContractError::UnexpectDealState {});
-                        }
+                // check deal state is correct
+                if deal.state != DealState::PendCustomerDeposit {
+                    return Err(ContractError::UnexpectDealState {});
+                }
 
-                        // check deposit amt
-                        if deal.amount != amount {
-                            return Err(ContractError::MismatchDepositAmount {0: deal.amount, 1: amount});
-                        }
+                // check deposit amt
+                if deal.amount != amount {
+                    return Err(ContractError::MismatchDepositAmount {0: deal.amount, 1: amount});
+                }
 
-                        // check if customer is indeed the customer shown in the deal
-                        let customer_should_be = &deal.customer;
-                        if customer_should_be != &from {
-                            return Err(ContractError::MismatchCustomer {});
-                        }
+                // check if customer is indeed the customer shown in the deal
+                let customer_should_be = &deal.customer;
+                if customer_should_be != &from {
+                    return Err(ContractError::MismatchCustomer {});
+                }
 
-                        // check if user is paying the correct snip token for deal making
-                        if info.sender != deal.deal_token.address {
-                            return Err(ContractError::InvalidDealToken {});
-                        }
+                // check if user is paying the correct snip token for deal making
+                if info.sender != deal.deal_token.address {
+                    return Err(ContractError::InvalidDealToken {});
+                }
 
-                        deal.customer_deposit = true;
-                        deal.state = DealState::PendDealerBankTransfer;
-                        deal.expiry = Some(now + DEAL_EXPIRY_TIME);
+                escrow_credit(deps.storage, &deal.deal_token, EscrowOwner::Deal(deal_id), amount)?;
 
-                        ACTIVE_DEALS.save(deps.storage, &active_deals.clone())?;
+                deal.customer_deposit = true;
+                deal.state = DealState::PendDealerBankTransfer;
+                deal.expiry = Some(now + DEAL_EXPIRY_TIME);
 
-                        Ok(
-                            Response::new().set_data(to_binary(&ExecuteAnswer::CustomerDeposit {
-                                status: ResponseStatus::Success,
-                            })?)
-                        )
-                    },
-                    None => {
-                        return Err(ContractError::NoMatchingDeal { 0: deal_id });
-                    }
-                }
+                save_active_deal(deps.storage, &deal)?;
+
+                Ok(
+                    Response::new().set_data(to_binary(&ExecuteAnswer::CustomerDeposit {
+                        status: ResponseStatus::Success,
+                    })?)
+                )
             }
             DepositAction::Dealer { post_id } => {
-                let mut active_posts = ACTIVE_POSTS.load(deps.storage)?;
+                let mut post = load_active_post(deps.storage, &post_id)?;
 
-                match active_posts.iter_mut().find(|x| x.post_id == post_id) {
-                    Some(post) => {
-                        // check deal state is correct
-                        if post.state != PostState::PendDealerDeposit {
-                            return Err(ContractError::UnexpectPostState {});
-                        }
+                // the first deposit opens the post, later ones top up an open sell post
+                let is_top_up = post.state == PostState::Open && post.dealer_deposit;
 
-                        // check deposit amt
-                        if post.amount != amount {
-                            return Err(ContractError::MismatchDepositAmount {0: post.amount, 1: amount});
-                        }
+                // check deal state is correct
+                if post.state != PostState::PendDealerDeposit && !is_top_up {
+                    return Err(ContractError::UnexpectPostState {});
+                }
 
-                        // check if dealer is indeed the dealer shown in the deal
-                        let dealer_should_be = &post.dealer;
-                        if dealer_should_be != &from {
-                            return Err(ContractError::MismatchDealer {});
-                        }
+                if post.is_expired(now) {
+                    return Err(ContractError::PostExpired(post.expiry));
+                }
 
-                        // check if user is paying the correct snip token for deal making
-                        let deal_token = post.deal_token.address.clone();
-                        if info.sender != deal_token {
-                            return Err(ContractError::InvalidDealToken {});
+                // check deposit amt
+                if is_top_up {
+                    // the bigger post still has to fit in the token limit
+                    if let Some(max_post_amount) = DEAL_TOKENS.get(deps.storage, &post.deal_token.address)
+                        .and_then(|x| x.max_post_amount) {
+                        if post.amount + amount > max_post_amount {
+                            return Err(ContractError::PostAmountMoreThanTokenMax(max_post_amount));
                         }
-
-                        post.dealer_deposit = true;
-                        post.state = PostState::Open;
-
-                        // save deal
-                        ACTIVE_POSTS.save(deps.storage, &active_posts.clone())?;
-
-                        Ok(
-                            Response::new().set_data(to_binary(&ExecuteAnswer::DealerDeposit {
-                                status: ResponseStatus::Success,
-                                sender: info.sender,
-                                deposit_token: deal_token,
-                            })?)
-                        )
-                    },
-                    None => {
-                        return Err(ContractError::NoMatchingPost { 0: post_id });
                     }
+                } else if post.amount != amount {
+                    return Err(ContractError::MismatchDepositAmount {0: post.amount, 1: amount});
+                }
+
+                // check if dealer is indeed the dealer shown in the deal
+                let dealer_should_be = &post.dealer;
+                if dealer_should_be != &from {
+                    return Err(ContractError::MismatchDealer {});
+                }
+
+                // check if user is paying the correct snip token for deal making
+                let deal_token = post.deal_token.address.clone();
+                if info.sender != deal_token {
+                    return Err(ContractError::InvalidDealToken {});
                 }
+
+                escrow_credit(deps.storage, &post.deal_token, EscrowOwner::Post(post_id), amount)?;
+
+                if is_top_up {
+                    post.amount += amount;
+                }
+                post.dealer_deposit = true;
+                post.state = PostState::Open;
+
+                // save post
+                save_active_post(deps.storage, &post)?;
+
+                Ok(
+                    Response::new().set_data(to_binary(&ExecuteAnswer::DealerDeposit {
+                        status: ResponseStatus::Success,
+                        sender: info.sender,
+                        deposit_token: deal_token,
+                    })?)
+                )
             }
         },
         None => {
@@ -302,37 +490,19 @@ pub fn add_post(
     deal_token: RawContract,
     amount: Uint128,  // number of crypto, 1_000_000 = 1 crypto
     min_amount: Uint128,
+    max_amount: Option<Uint128>,
     settle_currency: String,  // currency of the trade
     settle_price: Uint128,
 ) -> Result<Response, ContractError> {
-    // let support_currencies = vec![
-    //     "HKD".to_string(),
-    //     "USD".to_string(),
-    //     "EUR".to_string()
-    // ];
-
-    // if !support_currencies.contains(&settle_currency.clone()) {
-    //     return Err(ContractError::Std(StdError::generic_err("Unsupport currency")));
-    // }
-
-    if amount < Uint128::zero() {
-        return Err(ContractError::Std(StdError::generic_err("Amount cannot be lower than 0")));
-    }
-
-    if min_amount < Uint128::zero() {
-        return Err(ContractError::Std(StdError::generic_err("Min amount cannot be lower than 0")));
-    }
-
-    if settle_price < Uint128::zero() {
-        return Err(ContractError::Std(StdError::generic_err("Settle price cannot be lower than 0")));
-    }
+    validate_post_amounts(amount, min_amount, max_amount)?;
+    validate_settle_price(settle_price)?;
+    let settle_currency = normalize_currency(&settle_currency);
+    validate_settle_currency(deps.storage, &settle_currency)?;
 
     if !USER_PAYMENT_INFO.contains(deps.storage, &info.sender) {
         return Err(ContractError::MissPaymentInfo {});
     }
 
-    let mut active_posts = ACTIVE_POSTS.load(deps.storage)?;
-
     // update post id
     let new_id = MAX_POST_ID.load(deps.storage)? + Uint128::new(1);
     MAX_POST_ID.save(deps.storage, &new_id)?;
@@ -350,27 +520,38 @@ pub fn add_post(
 
     let deal_token_valid = deal_token.into_valid(deps.api)?;
 
-    let config = CONFIG.load(deps.storage)?;
+    // the token must be whitelisted, and the post size within its limits
+    let listed_token = DEAL_TOKENS.get(deps.storage, &deal_token_valid.address)
+        .ok_or(ContractError::InvalidDealToken {})?;
 
-    if deal_token_valid != config.deal_token_a && deal_token_valid != config.deal_token_b && deal_token_valid != config.deal_token_c {
+    if listed_token.contract != deal_token_valid {
         return Err(ContractError::InvalidDealToken {});
     }
 
-    active_posts.push(Post {
+    if amount < listed_token.min_post_amount {
+        return Err(ContractError::PostAmountLessThanTokenMin(listed_token.min_post_amount));
+    }
+
+    if let Some(max_post_amount) = listed_token.max_post_amount {
+        if amount > max_post_amount {
+            return Err(ContractError::PostAmountMoreThanTokenMax(max_post_amount));
+        }
+    }
+
+    save_active_post(deps.storage, &Post {
         post_id: new_id.clone(),
         is_dealer_buy: is_dealer_buy,
         deal_token: deal_token_valid,
         amount: amount,
         min_amount: min_amount,
+        max_amount: max_amount,
         settle_currency: settle_currency,
         settle_price: settle_price,
         dealer_deposit: false,
         dealer: info.sender.clone(),
         state: init_state,
         expiry: expiry,
-    });
-
-    ACTIVE_POSTS.save(deps.storage, &active_posts)?;
+    })?;
 
     Ok(
         Response::new().set_data(to_binary(&ExecuteAnswer::AddPost {
@@ -386,9 +567,7 @@ pub fn cancel_post(
     info: MessageInfo,
     post_id: Uint128,
 ) -> Result<Response, ContractError> {
-    let config = CONFIG.load(deps.storage)?;
-
-    let mut active_posts = ACTIVE_POSTS.load(deps.storage)?;
+    let post = load_active_post(deps.storage, &post_id)?;
     const SUPPORT_POST_STATES: [PostState;  2] = [
         PostState::Open,
         PostState::PendDealerDeposit,
@@ -396,45 +575,31 @@ pub fn cancel_post(
 
     let mut cosmos_msg: Option<CosmosMsg> = None;
 
-    match active_posts.iter_mut().find(|x| x.post_id == post_id) {
-        Some(post) => {
-
-            // check deal state is correct
-            if !SUPPORT_POST_STATES.contains(&post.state) {
-                return Err(ContractError::UnexpectDealState {});
-            }
+    // check deal state is correct
+    if !SUPPORT_POST_STATES.contains(&post.state) {
+        return Err(ContractError::UnexpectDealState {});
+    }
 
-            // check if dealer in the post matches sender
-            let dealer_should_be = &post.dealer;
-            if dealer_should_be != &info.sender {
-                return Err(ContractError::MismatchDealer {});
-            }
+    // check if dealer in the post matches sender
+    let dealer_should_be = &post.dealer;
+    if dealer_should_be != &info.sender {
+        return Err(ContractError::MismatchDealer {});
+    }
 
-            // If deal is open, it could mean dealer has already deposit crypto 
-            // if he wants to sell, we need to refund
-            if post.dealer_deposit {
-                // get the deal token detail first
-                let deal_token = post.deal_token.clone();
-
-                cosmos_msg = Some(transfer_msg(
-                    post.dealer.clone().into_string(),
-                    post.amount,
-                    None,
-                    None, 
-                    RESPONSE_BLOCK_SIZE,
-                    deal_token.code_hash,
-                    deal_token.address.into_string()
-                )?);
-            }
-        },
-        None => {
-            return Err(ContractError::NoMatchingPost { 0: post_id });
-        }
+    // If deal is open, it could mean dealer has already deposit crypto 
+    // if he wants to sell, we need to refund
+    if post.dealer_deposit {
+        cosmos_msg = Some(escrow_transfer(
+            deps.storage,
+            &post.deal_token,
+            EscrowOwner::Post(post_id),
+            &post.dealer,
+            post.amount
+        )?);
     }
 
     // remove post
-    active_posts.retain(|x| x.post_id != post_id);
-    ACTIVE_POSTS.save(deps.storage, &active_posts)?;
+    remove_post(deps.storage, &post_id)?;
 
     // return token if dealer already deposit
     if let Some(cosmos_msg) = cosmos_msg {
@@ -452,216 +617,512 @@ pub fn cancel_post(
     }
 }
 
-pub fn enter_deal(
+// Change the terms of a post, the deals already entered keep the terms they were entered at
+pub fn update_post(
     deps: DepsMut,
-    env: Env,
+    _env: Env,
     info: MessageInfo,
     post_id: Uint128,
-    amount: Uint128,
+    settle_price: Option<Uint128>,
+    min_amount: Option<Uint128>,
+    max_amount: Option<Uint128>,
+    clear_max_amount: Option<bool>,
+    amount: Option<Uint128>,
 ) -> Result<Response, ContractError> {
-    let now = Uint128::new(env.block.time.seconds() as u128);
+    let mut post = load_active_post(deps.storage, &post_id)?;
+    const SUPPORT_POST_STATES: [PostState;  2] = [
+        PostState::Open,
+        PostState::PendDealerDeposit,
+    ];
 
-    // Customer to accept a post and open a deal with dealer
-    let mut active_posts = ACTIVE_POSTS.load(deps.storage)?;
-    let mut active_deals = ACTIVE_DEALS.load(deps.storage)?;
+    // check post state is correct
+    if !SUPPORT_POST_STATES.contains(&post.state) {
+        return Err(ContractError::UnexpectPostState {});
+    }
 
-    const SUPPORT_STATES: [PostState;  1] = [
-        PostState::Open
-    ];
+    // check if dealer in the post matches sender
+    if post.dealer != info.sender {
+        return Err(ContractError::MismatchDealer {});
+    }
 
-    // Check if customer has already register for payment info
-    let user_payment_info = USER_PAYMENT_INFO.get(deps.storage, &info.sender.clone());
+    let mut event = Event::new("post_updated")
+        .add_attribute("post_id", post_id.to_string());
 
-    if user_payment_info.is_none() {
-        return Err(ContractError::MissPaymentInfo {});
+    if let Some(settle_price) = settle_price {
+        validate_settle_price(settle_price)?;
+        post.settle_price = settle_price;
+        event = event.add_attribute("settle_price", settle_price.to_string());
     }
 
-    match active_posts.iter_mut().find(|x| x.post_id == post_id) {
-        Some(post) => {
-            // list of things to check before deal is created
-            // check post state is correct
-            if !SUPPORT_STATES.contains(&post.state) {
-                return Err(ContractError::UnexpectPostState {});
-            }
+    if let Some(min_amount) = min_amount {
+        post.min_amount = min_amount;
+        event = event.add_attribute("min_amount", min_amount.to_string());
+    }
+
+    if let Some(max_amount) = max_amount {
+        post.max_amount = Some(max_amount);
+        event = event.add_attribute("max_amount", max_amount.to_string());
+    }
+
+    if clear_max_amount.unwrap_or(false) {
+        // setting and clearing the max in the same update is ambiguous
+        if max_amount.is_some() {
+            return Err(ContractError::InvalidMaxAmount {});
+        }
+        post.max_amount = None;
+        event = event.add_attribute("max_amount", "none");
+    }
+
+    if let Some(amount) = amount {
+        // a deposited post changes size through top up and WithdrawFromPost
+        if post.dealer_deposit {
+            return Err(ContractError::UnexpectPostState {});
+        }
 
-            // check if deal amount proposed by customer is less than min amount set by dealer
-            if amount < post.min_amount {
-                return Err(ContractError::AmountLessThanDealerReq {});
+        // the new size has to fit in the token limits, if the token is still listed
+        if let Some(listed_token) = DEAL_TOKENS.get(deps.storage, &post.deal_token.address) {
+            if amount < listed_token.min_post_amount {
+                return Err(ContractError::PostAmountLessThanTokenMin(listed_token.min_post_amount));
             }
 
-            // check if deal amount proposed by customer is more than the remaining post amount
-            if amount > post.amount {
-                return Err(ContractError::AmountMoreThanPost {});
+            if let Some(max_post_amount) = listed_token.max_post_amount {
+                if amount > max_post_amount {
+                    return Err(ContractError::PostAmountMoreThanTokenMax(max_post_amount));
+                }
             }
+        }
 
-            // deducing amount from the post
-            let new_post_amount = post.amount - amount;
-            post.amount = new_post_amount;
+        if amount.is_zero() {
+            return Err(ContractError::ZeroAmount {});
+        }
 
-            // create a new deal
-            // update deal id
-            let new_id = MAX_DEAL_ID.load(deps.storage)? + Uint128::new(1);
-            MAX_DEAL_ID.save(deps.storage, &new_id)?;
+        post.amount = amount;
+        event = event.add_attribute("amount", amount.to_string());
+    }
 
-            // deal state
-            let new_deal_state: DealState;
+    // like the max, the min is only checked against the size when either is changed
+    if (min_amount.is_some() || amount.is_some()) && post.min_amount > post.amount {
+        return Err(ContractError::MinAmountMoreThanAmount {});
+    }
 
-            if post.is_dealer_buy {
-                new_deal_state = DealState::PendCustomerDeposit;
-            } else {
-                new_deal_state = DealState::PendCustomerBankTransfer;
-            }
+    // the remaining amount of a partly filled post can be below the max,
+    // so it is only checked when the max or the size is changed
+    if let Some(post_max_amount) = post.max_amount {
+        if post_max_amount < post.min_amount {
+            return Err(ContractError::InvalidMaxAmount {});
+        }
 
-            // deal expiry
-            let deal_expiry = Some(now + DEAL_EXPIRY_TIME);
-
-            active_deals.push(Deal {
-                deal_id: new_id.clone(),
-                post_id: post_id.clone(),
-                is_dealer_buy: post.is_dealer_buy.clone(),
-                deal_token: post.deal_token.clone(),
-                amount: amount,
-                settle_currency: post.settle_currency.clone(),
-                settle_price: post.settle_price.clone(),
-                dealer_deposit: post.dealer_deposit.clone(),
-                customer_deposit: false,
-                dealer: post.dealer.clone(),
-                customer: info.sender.clone(),
-                state: new_deal_state,
-                resolver: None,
-                expiry: deal_expiry
-            });
-
-            // commit the change on deal + post
-            ACTIVE_POSTS.save(deps.storage, &active_posts.clone())?;
-            ACTIVE_DEALS.save(deps.storage, &active_deals.clone())?;
-
-            Ok(
-                Response::new().set_data(to_binary(&ExecuteAnswer::DealStageProcess {
-                    status: ResponseStatus::Success,
-                    deal_id: new_id
-                })?)
-            )
-        },
-        None => {
-            return Err(ContractError::NoMatchingPost { 0: post_id });
+        if (max_amount.is_some() || amount.is_some()) && post_max_amount > post.amount {
+            return Err(ContractError::InvalidMaxAmount {});
         }
     }
+
+    save_active_post(deps.storage, &post)?;
+
+    Ok(Response::new()
+        .add_event(event)
+        .set_data(to_binary(&ExecuteAnswer::UpdatePost {
+            status: ResponseStatus::Success,
+            post_id: post_id,
+        })?)
+    )
 }
 
-pub fn confirm_bank_transfer(
+// Take back part of the dealer deposit of an open sell post, the post stays open.
+// Only the remaining post amount can be withdrawn, the amount taken by deals is held by the deals.
+pub fn withdraw_from_post(
     deps: DepsMut,
-    env: Env,
+    _env: Env,
     info: MessageInfo,
-    deal_id: Uint128,
+    post_id: Uint128,
+    amount: Option<Uint128>,
 ) -> Result<Response, ContractError> {
-    let now = Uint128::new(env.block.time.seconds() as u128);
-
-    let mut active_deals = ACTIVE_DEALS.load(deps.storage)?;
-    const SUPPORT_STATES: [DealState;  2] = [
-        DealState::PendCustomerBankTransfer,
-        DealState::PendDealerBankTransfer
-    ];
-
-    match active_deals.iter_mut().find(|x| x.deal_id == deal_id) {
-        Some(deal) => {
-
-            // check deal state is correct
-            if !SUPPORT_STATES.contains(&deal.state) {
-                return Err(ContractError::UnexpectDealState {});
-            }
+    let mut post = load_active_post(deps.storage, &post_id)?;
 
-            if &deal.state == &DealState::PendCustomerBankTransfer {
-                // customer bank transfer should be confirmed by customer
-                let sender_should_be = &deal.customer.clone();
-                if sender_should_be != &info.sender {
-                    return Err(ContractError::MismatchCustomer {});
-                }
+    if post.state != PostState::Open || !post.dealer_deposit {
+        return Err(ContractError::UnexpectPostState {});
+    }
 
-                deal.customer_deposit = true;
-                deal.state = DealState::PendDealerSignOff;
-            } else {
-                // dealer bank transfer should be confirmed by dealer
-                let sender_should_be = &deal.dealer;
-                if sender_should_be != &info.sender {
-                    return Err(ContractError::MismatchDealer {});
-                }
+    // check if dealer in the post matches sender
+    if post.dealer != info.sender {
+        return Err(ContractError::MismatchDealer {});
+    }
 
-                deal.dealer_deposit = true;
-                deal.state = DealState::PendCustomerSignOff;
-            }
+    let amount = amount.unwrap_or(post.amount);
+    if amount.is_zero() {
+        return Err(ContractError::NothingToWithdraw {});
+    }
+    if amount > post.amount {
+        return Err(ContractError::AmountMoreThanPost {});
+    }
 
-            deal.expiry = Some(now + DEAL_EXPIRY_TIME);
+    let cosmos_msg = escrow_transfer(
+        deps.storage,
+        &post.deal_token,
+        EscrowOwner::Post(post_id),
+        &post.dealer,
+        amount
+    )?;
 
-            // save deal
-            ACTIVE_DEALS.save(deps.storage, &active_deals.clone())?;
+    post.amount -= amount;
+    save_active_post(deps.storage, &post)?;
 
-            Ok(
-                Response::new().set_data(to_binary(&ExecuteAnswer::DealStageProcess {
-                    status: ResponseStatus::Success,
-                    deal_id: deal_id
-                })?)
-            )
-        },
-        None => {
-            return Err(ContractError::NoMatchingDeal { 0: deal_id });
-        }
-    }
+    Ok(Response::new()
+        .add_message(cosmos_msg)
+        .set_data(to_binary(&ExecuteAnswer::WithdrawFromPost {
+            status: ResponseStatus::Success,
+            post_id: post_id,
+            amount: amount,
+        })?)
+    )
 }
 
-pub fn dispute_deal(
+pub fn renew_post(
     deps: DepsMut,
     env: Env,
     info: MessageInfo,
-    deal_id: Uint128,
+    post_id: Uint128,
 ) -> Result<Response, ContractError> {
-    let now = Uint128::new(env.block.time.seconds() as u128);
-
-    let mut active_deals = ACTIVE_DEALS.load(deps.storage)?;
-    const SUPPORT_STATES: [DealState;  2] = [
-        DealState::PendCustomerSignOff,
-        DealState::PendDealerSignOff
+    let mut post = load_active_post(deps.storage, &post_id)?;
+    const SUPPORT_POST_STATES: [PostState;  2] = [
+        PostState::Open,
+        PostState::PendDealerDeposit,
     ];
 
-    match active_deals.iter_mut().find(|x| x.deal_id == deal_id) {
-        Some(deal) => {
+    // check post state is correct
+    if !SUPPORT_POST_STATES.contains(&post.state) {
+        return Err(ContractError::UnexpectPostState {});
+    }
 
-            // check deal state is correct
-            if !SUPPORT_STATES.contains(&deal.state) {
-                return Err(ContractError::UnexpectDealState {});
-            }
+    // check if dealer in the post matches sender
+    if post.dealer != info.sender {
+        return Err(ContractError::MismatchDealer {});
+    }
 
-            if &deal.state == &DealState::PendCustomerSignOff {
-                // customer bank transfer should be confirmed by customer
-                let sender_should_be = &deal.customer.clone();
-                if sender_should_be != &info.sender {
-                    return Err(ContractError::MismatchCustomer {});
-                }
-            } else {
-                // dealer bank transfer should be confirmed by dealer
-                let sender_should_be = &deal.dealer;
-                if sender_should_be != &info.sender {
-                    return Err(ContractError::MismatchDealer {});
-                }
-            }
+    // expired posts can still be renewed until they are cleaned up by ExpirePosts
+    let now = Uint128::new(env.block.time.seconds() as u128);
+    post.expiry = now + POST_EXPIRY_TIME;
 
-            deal.state = DealState::Dispute;
-            deal.expiry = Some(now + DISPUTE_EXPIRY_TIME);
+    save_active_post(deps.storage, &post)?;
 
-            // save deal
-            ACTIVE_DEALS.save(deps.storage, &active_deals.clone())?;
+    Ok(Response::new().set_data(to_binary(&ExecuteAnswer::RenewPost {
+        status: ResponseStatus::Success,
+        post_id: post_id,
+        expiry: post.expiry,
+    })?))
+}
 
-            Ok(
-                Response::new().set_data(to_binary(&ExecuteAnswer::DealStageProcess {
-                    status: ResponseStatus::Success,
-                    deal_id: deal_id
-                })?)
-            )
-        },
-        None => {
-            return Err(ContractError::NoMatchingDeal { 0: deal_id });
+pub fn expire_posts(
+    deps: DepsMut,
+    env: Env,
+    _info: MessageInfo,
+    start_after: Option<Uint128>,
+    limit: Option<u32>,
+) -> Result<Response, ContractError> {
+    let now = Uint128::new(env.block.time.seconds() as u128);
+    let limit = limit.unwrap_or(EXPIRE_POSTS_DEFAULT_LIMIT).clamp(1, EXPIRE_POSTS_MAX_LIMIT) as u128;
+
+    // post ids are sequential, check the next `limit` ids whether they are active or not
+    // so the gas of one call doesn't grow with the number of posts
+    let max_id = MAX_POST_ID.load(deps.storage)?.u128();
+    let from = start_after.map_or(1, |x| x.u128().saturating_add(1));
+    let to = from.saturating_add(limit - 1).min(max_id);
+
+    let mut expired_posts: Vec<Post> = Vec::new();
+    for post_id in from..=to {
+        let post_id = Uint128::new(post_id);
+        if !ACTIVE_POST_IDS.contains(deps.storage, &post_id) {
+            continue;
+        }
+
+        let post = load_active_post(deps.storage, &post_id)?;
+        if post.is_expired(now) {
+            expired_posts.push(post);
+        }
+    }
+
+    let next_start_after = if to < max_id { Some(Uint128::new(to)) } else { None };
+
+    let mut messages: Vec<CosmosMsg> = Vec::new();
+    let mut post_ids: Vec<Uint128> = Vec::new();
+
+    for post in expired_posts {
+        // refund what is left of the dealer deposit,
+        // the amount already taken by deals stays with those deals
+        if post.dealer_deposit && !post.amount.is_zero() {
+            messages.push(escrow_transfer(
+                deps.storage,
+                &post.deal_token,
+                EscrowOwner::Post(post.post_id),
+                &post.dealer,
+                post.amount
+            )?);
+        }
+
+        remove_post(deps.storage, &post.post_id)?;
+        post_ids.push(post.post_id);
+    }
+
+    Ok(Response::new()
+        .add_messages(messages)
+        .set_data(to_binary(&ExecuteAnswer::ExpirePosts {
+            status: ResponseStatus::Success,
+            post_ids,
+            next_start_after,
+        })?)
+    )
+}
+
+// Move a batch of the 0.1.0 past deals to the keyed storage
+pub fn migrate_past_deals(
+    deps: DepsMut,
+    _env: Env,
+    _info: MessageInfo,
+    limit: Option<u32>,
+) -> Result<Response, ContractError> {
+    let limit = limit.unwrap_or(MIGRATE_PAST_DEALS_DEFAULT_LIMIT).clamp(1, MIGRATE_PAST_DEALS_MAX_LIMIT);
+    let (migrated, remaining) = migrate::migrate_past_deals(deps.storage, limit)?;
+
+    Ok(Response::new()
+        .set_data(to_binary(&ExecuteAnswer::MigratePastDeals {
+            status: ResponseStatus::Success,
+            migrated,
+            remaining,
+        })?)
+    )
+}
+
+pub fn enter_deal(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    post_id: Uint128,
+    amount: Uint128,
+) -> Result<Response, ContractError> {
+    let now = Uint128::new(env.block.time.seconds() as u128);
+
+    const SUPPORT_STATES: [PostState;  1] = [
+        PostState::Open
+    ];
+
+    // Check if customer has already register for payment info
+    let user_payment_info = USER_PAYMENT_INFO.get(deps.storage, &info.sender.clone());
+
+    if user_payment_info.is_none() {
+        return Err(ContractError::MissPaymentInfo {});
+    }
+
+    // Customer to accept a post and open a deal with dealer
+    let mut post = load_active_post(deps.storage, &post_id)?;
+
+    // list of things to check before deal is created
+    // check post state is correct
+    if !SUPPORT_STATES.contains(&post.state) {
+        return Err(ContractError::UnexpectPostState {});
+    }
+
+    // check post is not expired
+    if post.is_expired(now) {
+        return Err(ContractError::PostExpired(post.expiry));
+    }
+
+    validate_not_self_deal(&post.dealer, &info.sender)?;
+    validate_deal_amount(amount)?;
+
+    // check the customer is not cooling down and both parties are within their deal limits
+    let limits = CONFIG.load(deps.storage)?.deal_limits;
+
+    if let Some(until) = USER_COOL_DOWN.get(deps.storage, &info.sender) {
+        if until > now {
+            return Err(ContractError::DealCoolDown(until));
+        }
+    }
+
+    if let Some(max_deals) = limits.max_customer_deals {
+        if load_active_deal_count(deps.storage, &info.sender).as_customer >= max_deals {
+            return Err(ContractError::ConcurrentDealNotAllowed(max_deals));
+        }
+    }
+
+    if let Some(max_deals) = limits.max_dealer_deals {
+        if load_active_deal_count(deps.storage, &post.dealer).as_dealer >= max_deals {
+            return Err(ContractError::ConcurrentDealNotAllowed(max_deals));
+        }
+    }
+
+    // check if deal amount proposed by customer is less than min amount set by dealer
+    if amount < post.min_amount {
+        return Err(ContractError::AmountLessThanDealerReq {});
+    }
+
+    // check if deal amount proposed by customer is more than the max amount set by dealer
+    if let Some(max_amount) = post.max_amount {
+        if amount > max_amount {
+            return Err(ContractError::AmountMoreThanDealerMax {});
+        }
+    }
+
+    // check if deal amount proposed by customer is more than the remaining post amount
+    if amount > post.amount {
+        return Err(ContractError::AmountMoreThanPost {});
+    }
+
+    // deducing amount from the post
+    let new_post_amount = post.amount - amount;
+    post.amount = new_post_amount;
+
+    // create a new deal
+    // update deal id
+    let new_id = MAX_DEAL_ID.load(deps.storage)? + Uint128::new(1);
+    MAX_DEAL_ID.save(deps.storage, &new_id)?;
+
+    // deal state
+    let new_deal_state: DealState;
+
+    if post.is_dealer_buy {
+        new_deal_state = DealState::PendCustomerDeposit;
+    } else {
+        new_deal_state = DealState::PendCustomerBankTransfer;
+    }
+
+    // deal expiry
+    let deal_expiry = Some(now + DEAL_EXPIRY_TIME);
+
+    // the deal takes its amount out of the dealer deposit of the post
+    if post.dealer_deposit {
+        escrow_move(deps.storage, &post.deal_token, EscrowOwner::Post(post_id), EscrowOwner::Deal(new_id), amount)?;
+    }
+
+    // commit the change on deal + post
+    save_active_deal(deps.storage, &Deal {
+        deal_id: new_id.clone(),
+        post_id: post_id.clone(),
+        is_dealer_buy: post.is_dealer_buy.clone(),
+        deal_token: post.deal_token.clone(),
+        amount: amount,
+        settle_currency: post.settle_currency.clone(),
+        settle_price: post.settle_price.clone(),
+        dealer_deposit: post.dealer_deposit.clone(),
+        customer_deposit: false,
+        dealer: post.dealer.clone(),
+        customer: info.sender.clone(),
+        state: new_deal_state,
+        resolver: None,
+        expiry: deal_expiry,
+        close_reason: None,
+        votes: vec![],
+        assigned_moderator: None,
+        assignment_expiry: None,
+        escalated: false,
+    })?;
+    save_active_post(deps.storage, &post)?;
+
+    Ok(
+        Response::new().set_data(to_binary(&ExecuteAnswer::DealStageProcess {
+            status: ResponseStatus::Success,
+            deal_id: new_id
+        })?)
+    )
+}
+
+pub fn confirm_bank_transfer(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    deal_id: Uint128,
+) -> Result<Response, ContractError> {
+    let now = Uint128::new(env.block.time.seconds() as u128);
+
+    let mut deal = load_active_deal(deps.storage, &deal_id)?;
+    const SUPPORT_STATES: [DealState;  2] = [
+        DealState::PendCustomerBankTransfer,
+        DealState::PendDealerBankTransfer
+    ];
+
+    // check deal state is correct
+    if !SUPPORT_STATES.contains(&deal.state) {
+        return Err(ContractError::UnexpectDealState {});
+    }
+
+    if &deal.state == &DealState::PendCustomerBankTransfer {
+        // customer bank transfer should be confirmed by customer
+        let sender_should_be = &deal.customer.clone();
+        if sender_should_be != &info.sender {
+            return Err(ContractError::MismatchCustomer {});
+        }
+
+        deal.customer_deposit = true;
+        deal.state = DealState::PendDealerSignOff;
+    } else {
+        // dealer bank transfer should be confirmed by dealer
+        let sender_should_be = &deal.dealer;
+        if sender_should_be != &info.sender {
+            return Err(ContractError::MismatchDealer {});
+        }
+
+        deal.dealer_deposit = true;
+        deal.state = DealState::PendCustomerSignOff;
+    }
+
+    deal.expiry = Some(now + DEAL_EXPIRY_TIME);
+
+    // save deal
+    save_active_deal(deps.storage, &deal)?;
+
+    Ok(
+        Response::new().set_data(to_binary(&ExecuteAnswer::DealStageProcess {
+            status: ResponseStatus::Success,
+            deal_id: deal_id
+        })?)
+    )
+}
+
+pub fn dispute_deal(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    deal_id: Uint128,
+) -> Result<Response, ContractError> {
+    let now = Uint128::new(env.block.time.seconds() as u128);
+
+    let mut deal = load_active_deal(deps.storage, &deal_id)?;
+    const SUPPORT_STATES: [DealState;  2] = [
+        DealState::PendCustomerSignOff,
+        DealState::PendDealerSignOff
+    ];
+
+    // check deal state is correct
+    if !SUPPORT_STATES.contains(&deal.state) {
+        return Err(ContractError::UnexpectDealState {});
+    }
+
+    if &deal.state == &DealState::PendCustomerSignOff {
+        // customer bank transfer should be confirmed by customer
+        let sender_should_be = &deal.customer.clone();
+        if sender_should_be != &info.sender {
+            return Err(ContractError::MismatchCustomer {});
+        }
+    } else {
+        // dealer bank transfer should be confirmed by dealer
+        let sender_should_be = &deal.dealer;
+        if sender_should_be != &info.sender {
+            return Err(ContractError::MismatchDealer {});
         }
     }
+
+    deal.state = DealState::Dispute;
+    deal.expiry = Some(now + DISPUTE_EXPIRY_TIME);
+    assign_moderator(deps.storage, &mut deal, now)?;
+
+    // save deal
+    save_active_deal(deps.storage, &deal)?;
+
+    Ok(
+        Response::new().set_data(to_binary(&ExecuteAnswer::DealStageProcess {
+            status: ResponseStatus::Success,
+            deal_id: deal_id
+        })?)
+    )
 }
 
 pub fn resolve_deal(
@@ -679,166 +1140,474 @@ pub fn resolve_deal(
     ];
 
     let config = CONFIG.load(deps.storage)?;
-    let mut active_deals = ACTIVE_DEALS.load(deps.storage)?;
-    let mut past_deals = PAST_DEALS.load(deps.storage)?;
+    let deal = load_active_deal(deps.storage, &deal_id)?;
 
-    let deal_post_id: Uint128;
+    // check deal state is correct
+    if !SUPPORT_STATES.contains(&deal.state) {
+        return Err(ContractError::UnexpectDealState {});
+    }
 
-    let curr_mods = MODERATORS.load(deps.storage)?;
+    // Dispute, a moderator votes to still resolve the case
+    if &deal.state == &DealState::Dispute {
+        return vote_on_dispute(deps, now, &info.sender, deal, DisputeDecision::Release);
+    }
 
-    let cosmos_msg: Option<CosmosMsg>;
-    let commission: Uint128;
+    // normal customer sign off [Dealer buying crypto from Customer]
+    if &deal.state == &DealState::PendCustomerSignOff {
+        // dealer bank transfer should be confirmed by customer before expiry
+        // but can also be resolved by dealer after expiry
+        let customer = deal.customer.clone();
 
-    match active_deals.iter_mut().find(|x| x.deal_id == deal_id) {
-        Some(deal) => {
+        if &deal.expiry.unwrap() > &now {
+            let sender_should_be = &customer;
+            if sender_should_be != &info.sender {
+                return Err(ContractError::MismatchCustomer {});
+            }
+        } else {
+            let senders_should_be = vec![
+                &customer,
+                &deal.dealer,
+            ];
+
+            if !senders_should_be.contains(&&info.sender) {
+                return Err(ContractError::Unauthorized {})
+            }
+        }
+
+    // normal dealer sign off [Dealer selling crypto to Customer]
+    } else if &deal.state == &DealState::PendDealerSignOff {
+        // customer bank transfer should be confirmed by dealer
+        // but can also be resolved by customer after expiry
+        let customer = deal.customer.clone();
 
-            // check deal state is correct
-            if !SUPPORT_STATES.contains(&deal.state) {
-                return Err(ContractError::UnexpectDealState {});
+        if &deal.expiry.unwrap() > &now {
+            let sender_should_be = &deal.dealer;
+            if sender_should_be != &info.sender {
+                return Err(ContractError::MismatchDealer {});
+            }
+        } else {
+            let senders_should_be = vec![
+                &customer,
+                &deal.dealer,
+            ];
+
+            if !senders_should_be.contains(&&info.sender) {
+                return Err(ContractError::Unauthorized {})
             }
+        }
+    }
 
-            deal_post_id = deal.post_id.clone();
+    let cosmos_msg = release_deal(deps.storage, &config, deal, &info.sender)?;
 
-            // normal customer sign off [Dealer buying crypto from Customer]
-            if &deal.state == &DealState::PendCustomerSignOff {
-                // dealer bank transfer should be confirmed by customer before expiry
-                // but can also be resolved by dealer after expiry
-                let customer = deal.customer.clone();
+    Ok(Response::new().add_message(cosmos_msg))
+}
 
-                if &deal.expiry.unwrap() > &now {
-                    let sender_should_be = &customer;
-                    if sender_should_be != &info.sender {
-                        return Err(ContractError::MismatchCustomer {});
-                    }
-                } else {
-                    let senders_should_be = vec![
-                        &customer,
-                        &deal.dealer,
-                    ];
-
-                    if !senders_should_be.contains(&&info.sender) {
-                        return Err(ContractError::Unauthorized {})
-                    }
-                }
+// Pay the crypto buyer less the commission and archive the deal as resolved
+fn release_deal(
+    storage: &mut dyn Storage,
+    config: &Config,
+    mut deal: Deal,
+    resolver: &Addr,
+) -> Result<CosmosMsg, ContractError> {
+    let deal_id = deal.deal_id;
+    let deal_post_id = deal.post_id;
+
+    let commission = calculate_commission(deal.amount.clone(), config.deal_commission.clone());
+    let payout = deal.amount.clone() - &commission;
+
+    let cosmos_msg = escrow_transfer(
+        storage,
+        &deal.deal_token,
+        EscrowOwner::Deal(deal_id),
+        deal.payer(),
+        payout
+    )?;
 
-                commission = calculate_commission(deal.amount.clone(), config.deal_commission.clone());
-                let payout = deal.amount.clone() - &commission;
-
-                let deal_token = deal.deal_token.clone();
-
-                cosmos_msg = Some(transfer_msg(
-                    deal.dealer.clone().into_string(),
-                    payout,
-                    None,
-                    None, 
-                    RESPONSE_BLOCK_SIZE,
-                    deal_token.code_hash,
-                    deal_token.address.into_string()
-                )?);
-
-            // normal dealer sign off [Dealer selling crypto to Customer]
-            } else if &deal.state == &DealState::PendDealerSignOff {
-                // customer bank transfer should be confirmed by dealer
-                // but can also be resolved by customer after expiry
-                let customer = deal.customer.clone();
-
-                if &deal.expiry.unwrap() > &now {
-                    let sender_should_be = &deal.dealer;
-                    if sender_should_be != &info.sender {
-                        return Err(ContractError::MismatchDealer {});
-                    }
-                } else {
-                    let senders_should_be = vec![
-                        &customer,
-                        &deal.dealer,
-                    ];
-
-                    if !senders_should_be.contains(&&info.sender) {
-                        return Err(ContractError::Unauthorized {})
-                    }
-                }
+    deal.state = DealState::Resolve;
+    deal.resolver = Some(resolver.clone());
 
-                commission = calculate_commission(deal.amount.clone(), config.deal_commission.clone());
-                let payout = deal.amount.clone() - &commission;
-
-                let deal_token = deal.deal_token.clone();
-
-                cosmos_msg = Some(transfer_msg(
-                    customer.into_string(),
-                    payout,
-                    None,
-                    None, 
-                    RESPONSE_BLOCK_SIZE,
-                    deal_token.code_hash,
-                    deal_token.address.into_string()
-                )?);
-
-            // Dispute, and admin decides to still resolve the case
-            } else {
-                if !curr_mods.contains(&info.sender.clone())  {
-                    return Err(ContractError::Unauthorized {});
-                }
+    // archive deal into past deals
+    archive_deal(storage, &deal)?;
 
-                let receiver = if deal.is_dealer_buy
-                    { deal.dealer.clone() } 
-                    else { deal.customer.clone() };
+    // add revenue of the deal token
+    add_revenue(storage, &deal.deal_token, commission)?;
+    escrow_move(storage, &deal.deal_token, EscrowOwner::Deal(deal_id), EscrowOwner::Revenue, commission)?;
 
-                commission = calculate_commission(deal.amount.clone(), config.deal_commission.clone());
-                let payout = deal.amount.clone() - &commission;
+    // remove post if its zero balance
+    if ACTIVE_POST_IDS.contains(storage, &deal_post_id) {
+        let post = load_active_post(storage, &deal_post_id)?;
+        if post.amount == Uint128::zero() {
+            remove_post(storage, &deal_post_id)?;
+        }
+    }
 
-                let deal_token = deal.deal_token.clone();
+    Ok(cosmos_msg)
+}
 
-                cosmos_msg = Some(transfer_msg(
-                    receiver.into_string(),
-                    payout,
-                    None,
-                    None, 
-                    RESPONSE_BLOCK_SIZE,
-                    deal_token.code_hash,
-                    deal_token.address.into_string()
-                )?);
-            }
+// Refund the deposit of a disputed deal and archive it as cancelled
+fn refund_disputed_deal(
+    storage: &mut dyn Storage,
+    config: &Config,
+    mut deal: Deal,
+    resolver: &Addr,
+) -> Result<Vec<CosmosMsg>, ContractError> {
+    let mut messages: Vec<CosmosMsg> = Vec::new();
+
+    // refund the customer deposit, the dealer deposit is returned below
+    if deal.is_dealer_buy {
+        messages.push(escrow_transfer(
+            storage,
+            &deal.deal_token,
+            EscrowOwner::Deal(deal.deal_id),
+            &deal.customer,
+            deal.amount
+        )?);
+    }
 
-            deal.state = DealState::Resolve;
-            deal.resolver = Some(info.sender.clone());
+    deal.state = DealState::CancelAsDispute;
+    deal.resolver = Some(resolver.clone());
+
+    // archive deal into past deals
+    archive_deal(storage, &deal)?;
+
+    // the amount taken from the post goes back to the post or the dealer
+    if let Some(refund_msg) = return_deal_amount(storage, &config.deal_cancel_policy, &deal)? {
+        messages.push(refund_msg);
+    }
+
+    Ok(messages)
+}
+
+// Split a disputed deal, the crypto buyer gets to_receiver_bps of the amount less the commission on that part,
+// the depositor gets the rest back
+fn split_disputed_deal(
+    storage: &mut dyn Storage,
+    config: &Config,
+    mut deal: Deal,
+    to_receiver_bps: Uint128,
+    resolver: &Addr,
+) -> Result<Vec<CosmosMsg>, ContractError> {
+    let deal_id = deal.deal_id;
+    let deal_post_id = deal.post_id;
+
+    let receiver_amount = deal.amount.multiply_ratio(to_receiver_bps, COMMISSION_MULTIPLIER);
+    let depositor_amount = deal.amount - receiver_amount;
+
+    // commission is only taken on the released part
+    let commission = calculate_commission(receiver_amount, config.deal_commission.clone());
+    let payout = receiver_amount - commission;
+
+    let mut messages: Vec<CosmosMsg> = Vec::new();
+
+    if !payout.is_zero() {
+        messages.push(escrow_transfer(
+            storage,
+            &deal.deal_token,
+            EscrowOwner::Deal(deal_id),
+            &deal.payer().clone(),
+            payout
+        )?);
+    }
+
+    if !depositor_amount.is_zero() {
+        messages.push(escrow_transfer(
+            storage,
+            &deal.deal_token,
+            EscrowOwner::Deal(deal_id),
+            &deal.payee().clone(),
+            depositor_amount
+        )?);
+    }
+
+    deal.state = DealState::SettledSplit;
+    deal.resolver = Some(resolver.clone());
+
+    // archive deal into past deals
+    archive_deal(storage, &deal)?;
+
+    // add revenue of the deal token
+    add_revenue(storage, &deal.deal_token, commission)?;
+    escrow_move(storage, &deal.deal_token, EscrowOwner::Deal(deal_id), EscrowOwner::Revenue, commission)?;
+
+    // remove post if its zero balance
+    if ACTIVE_POST_IDS.contains(storage, &deal_post_id) {
+        let post = load_active_post(storage, &deal_post_id)?;
+        if post.amount == Uint128::zero() {
+            remove_post(storage, &deal_post_id)?;
+        }
+    }
+
+    Ok(messages)
+}
+
+pub fn submit_evidence(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    deal_id: Uint128,
+    content_hash: String,
+    note: String,
+) -> Result<Response, ContractError> {
+    let now = Uint128::new(env.block.time.seconds() as u128);
+
+    let deal = load_active_deal(deps.storage, &deal_id)?;
+
+    // check deal state is correct
+    if &deal.state != &DealState::Dispute {
+        return Err(ContractError::UnexpectDealState {});
+    }
+
+    if &deal.customer != &info.sender && &deal.dealer != &info.sender {
+        return Err(ContractError::Unauthorized {});
+    }
+
+    validate_evidence(&content_hash, &note)?;
+
+    let mut evidence = DEAL_EVIDENCE.get(deps.storage, &deal_id).unwrap_or_default();
+    if evidence.len() >= MAX_EVIDENCE_PER_DEAL {
+        return Err(ContractError::TooMuchEvidence(MAX_EVIDENCE_PER_DEAL));
+    }
+
+    evidence.push(Evidence {
+        submitter: info.sender.clone(),
+        content_hash,
+        note,
+        time: now,
+    });
+    DEAL_EVIDENCE.insert(deps.storage, &deal_id, &evidence)?;
+
+    Ok(
+        Response::new().set_data(to_binary(&ExecuteAnswer::SubmitEvidence {
+            status: ResponseStatus::Success,
+            deal_id: deal_id
+        })?)
+    )
+}
+
+pub fn reassign_dispute(
+    deps: DepsMut,
+    env: Env,
+    _info: MessageInfo,
+    deal_id: Uint128,
+) -> Result<Response, ContractError> {
+    let now = Uint128::new(env.block.time.seconds() as u128);
+
+    let mut deal = load_active_deal(deps.storage, &deal_id)?;
+    let curr_mods = MODERATORS.load(deps.storage)?;
 
-            // archive deal into past deals
-            past_deals.push(deal.clone());
-            PAST_DEALS.save(deps.storage, &past_deals)?;
+    // check deal state is correct
+    if &deal.state != &DealState::Dispute {
+        return Err(ContractError::UnexpectDealState {});
+    }
+
+    // the assignment can be taken over once it expired, or straight away if the moderator was removed
+    if let (Some(moderator), Some(expiry)) = (&deal.assigned_moderator, deal.assignment_expiry) {
+        if curr_mods.contains(moderator) && expiry > now {
+            return Err(ContractError::AssignmentNotExpired(expiry));
+        }
+    }
 
-            // add revenue
-            let new_revenue = TOKEN_REVENUE.load(deps.storage)? + &commission;
-            TOKEN_REVENUE.save(deps.storage, &new_revenue)?;
+    assign_moderator(deps.storage, &mut deal, now)?;
+    save_active_deal(deps.storage, &deal)?;
+
+    Ok(
+        Response::new().set_data(to_binary(&ExecuteAnswer::ReassignDispute {
+            status: ResponseStatus::Success,
+            deal_id: deal_id,
+            moderator: deal.assigned_moderator,
+        })?)
+    )
+}
+
+// Assign a dispute to the next moderator, round-robin over MODERATORS.
+// The parties of the deal and the moderators that already voted are skipped,
+// the moderator assigned before is only picked again if nobody else is left.
+fn assign_moderator(storage: &mut dyn Storage, deal: &mut Deal, now: Uint128) -> StdResult<()> {
+    let curr_mods = MODERATORS.load(storage)?;
+    let cursor = MODERATOR_CURSOR.may_load(storage)?.unwrap_or_default() as usize;
+    let previous = deal.assigned_moderator.take();
+
+    let eligible: Vec<usize> = (0..curr_mods.len())
+        .map(|i| (cursor + i) % curr_mods.len())
+        .filter(|i| {
+            let moderator = &curr_mods[*i];
+            moderator != &deal.customer
+                && moderator != &deal.dealer
+                && !deal.votes.iter().any(|vote| &vote.moderator == moderator)
+        })
+        .collect();
+
+    let next = eligible.iter()
+        .find(|i| Some(&curr_mods[**i]) != previous.as_ref())
+        .or(eligible.first());
+
+    match next {
+        Some(i) => {
+            deal.assigned_moderator = Some(curr_mods[*i].clone());
+            deal.assignment_expiry = Some(now + MODERATOR_ASSIGNMENT_TIME);
+            MODERATOR_CURSOR.save(storage, &(((i + 1) % curr_mods.len()) as u32))?;
         },
         None => {
-            return Err(ContractError::NoMatchingDeal { 0: deal_id });
-        }
+            deal.assignment_expiry = None;
+        },
+    }
+
+    Ok(())
+}
+
+// Fallback for a dispute the moderators did not settle in time
+pub fn expire_dispute(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    deal_id: Uint128,
+) -> Result<Response, ContractError> {
+    let now = Uint128::new(env.block.time.seconds() as u128);
+
+    let config = CONFIG.load(deps.storage)?;
+    let mut deal = load_active_deal(deps.storage, &deal_id)?;
+
+    // check deal state is correct
+    if &deal.state != &DealState::Dispute {
+        return Err(ContractError::UnexpectDealState {});
+    }
+
+    if &deal.customer != &info.sender && &deal.dealer != &info.sender {
+        return Err(ContractError::Unauthorized {});
     }
 
-    // remove deal
-    active_deals.retain(|x| x.deal_id != deal_id);
-    ACTIVE_DEALS.save(deps.storage, &active_deals)?;
+    if &deal.expiry.unwrap() > &now {
+        return Err(ContractError::DealNotExpired(deal.expiry.unwrap()));
+    }
+
+    let mut messages: Vec<CosmosMsg> = Vec::new();
 
-    // change and remove post if its zero balance
-    let mut is_remove_post = false;
-    let mut active_posts = ACTIVE_POSTS.load(deps.storage)?;
-    match active_posts.iter_mut().find(|x| x.post_id == deal_post_id) {
-        Some(post) => {
-            if post.amount == Uint128::zero() {
-                is_remove_post = true;
+    match config.dispute_timeout_outcome {
+        DisputeTimeoutOutcome::RefundDepositor => {
+            messages.append(&mut refund_disputed_deal(deps.storage, &config, deal, &info.sender)?);
+        },
+        DisputeTimeoutOutcome::EscalateToAdmins => {
+            if deal.escalated {
+                return Err(ContractError::AlreadyEscalated);
             }
+
+            // moderators can still vote, the admins settle it with AdminCloseDeal
+            deal.escalated = true;
+            save_active_deal(deps.storage, &deal)?;
         },
-        None => {
-            is_remove_post = false;
+    }
+
+    Ok(Response::new()
+        .add_messages(messages)
+        .add_event(Event::new("dispute_expired")
+            .add_attribute("deal_id", deal_id.to_string())
+            .add_attribute("outcome", format!("{:?}", config.dispute_timeout_outcome)))
+        .set_data(to_binary(&ExecuteAnswer::DealStageProcess {
+            status: ResponseStatus::Success,
+            deal_id: deal_id
+        })?)
+    )
+}
+
+pub fn cast_dispute_vote(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    deal_id: Uint128,
+    decision: DisputeDecision,
+) -> Result<Response, ContractError> {
+    let now = Uint128::new(env.block.time.seconds() as u128);
+
+    let deal = load_active_deal(deps.storage, &deal_id)?;
+
+    // check deal state is correct
+    if &deal.state != &DealState::Dispute {
+        return Err(ContractError::UnexpectDealState {});
+    }
+
+    vote_on_dispute(deps, now, &info.sender, deal, decision)
+}
+
+// Record the vote of a moderator on a disputed deal.
+// The deal settles once one decision has the votes of dispute_quorum moderators.
+fn vote_on_dispute(
+    deps: DepsMut,
+    now: Uint128,
+    sender: &Addr,
+    mut deal: Deal,
+    decision: DisputeDecision,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    let curr_mods = MODERATORS.load(deps.storage)?;
+
+    // a moderator cannot arbitrate their own deal
+    if !curr_mods.contains(sender) || &deal.customer == sender || &deal.dealer == sender {
+        return Err(ContractError::Unauthorized {});
+    }
+
+    if deal.votes.iter().any(|vote| &vote.moderator == sender) {
+        return Err(ContractError::AlreadyVoted);
+    }
+
+    if let DisputeDecision::Split { to_receiver_bps } = decision {
+        if to_receiver_bps > COMMISSION_MULTIPLIER {
+            return Err(ContractError::InvalidSplit(to_receiver_bps));
         }
     }
 
-    if is_remove_post {
-        active_posts.retain(|x| x.post_id != deal_post_id);
-        ACTIVE_POSTS.save(deps.storage, &active_posts)?;
+    deal.votes.push(DisputeVote {
+        moderator: sender.clone(),
+        decision: decision.clone(),
+        time: now,
+    });
+
+    // votes of removed moderators don't count,
+    // and the quorum is capped by the moderators able to vote so a dispute can always settle
+    let eligible = curr_mods.iter()
+        .filter(|moderator| *moderator != &deal.customer && *moderator != &deal.dealer)
+        .count() as u32;
+    let quorum = config.dispute_quorum.min(eligible).max(1);
+    let votes = deal.votes.iter()
+        .filter(|vote| vote.decision == decision && curr_mods.contains(&vote.moderator))
+        .count() as u32;
+
+    let deal_id = deal.deal_id;
+    let event = Event::new("dispute_vote")
+        .add_attribute("deal_id", deal_id.to_string())
+        .add_attribute("decision", format!("{:?}", decision))
+        .add_attribute("votes", votes.to_string())
+        .add_attribute("quorum", quorum.to_string());
+
+    let mut messages: Vec<CosmosMsg> = Vec::new();
+
+    if votes < quorum {
+        // the assigned moderator acted, the next vote is up to another one
+        if deal.assigned_moderator.as_ref() == Some(sender) {
+            assign_moderator(deps.storage, &mut deal, now)?;
+        }
+        save_active_deal(deps.storage, &deal)?;
+    } else {
+        match decision {
+            DisputeDecision::Release => {
+                messages.push(release_deal(deps.storage, &config, deal, sender)?);
+            },
+            DisputeDecision::Refund => {
+                messages.append(&mut refund_disputed_deal(deps.storage, &config, deal, sender)?);
+            },
+            DisputeDecision::Split { to_receiver_bps } => {
+                messages.append(&mut split_disputed_deal(deps.storage, &config, deal, to_receiver_bps, sender)?);
+            },
+        }
     }
 
-    Ok(Response::new().add_message(cosmos_msg.unwrap()))
+    Ok(Response::new()
+        .add_messages(messages)
+        .add_event(event)
+        .set_data(to_binary(&ExecuteAnswer::DealStageProcess {
+            status: ResponseStatus::Success,
+            deal_id: deal_id
+        })?)
+    )
 }
 
 fn calculate_commission(amount: Uint128, comm_bps: Uint128) -> Uint128 {
@@ -860,242 +1629,265 @@ pub fn cancel_deal(
 
     let now = Uint128::new(env.block.time.seconds() as u128);
 
+    let mut deal = load_active_deal(deps.storage, &deal_id)?;
+
     let config = CONFIG.load(deps.storage)?;
-    let mut active_deals = ACTIVE_DEALS.load(deps.storage)?;
-    let mut past_deals = PAST_DEALS.load(deps.storage)?;
+    let mut messages: Vec<CosmosMsg> = Vec::new();
 
-    let curr_mods = MODERATORS.load(deps.storage)?;
-    // let deal_post_id: Uint128;
-    // let deal_post_amount: Uint128;
-    let mut cosmos_msg: Option<CosmosMsg> = None;
+    // check deal state is correct
+    if !SUPPORT_STATES.contains(&deal.state) {
+        return Err(ContractError::UnexpectDealState {});
+    }
 
-    match active_deals.iter_mut().find(|x| x.deal_id == deal_id) {
-        Some(deal) => {
+    // Dispute + cancel = a moderator votes to refund to the customer/dealer
+    if &deal.state == &DealState::Dispute {
+        return vote_on_dispute(deps, now, &info.sender, deal, DisputeDecision::Refund);
+    }
 
-            // check deal state is correct
-            if !SUPPORT_STATES.contains(&deal.state) {
-                return Err(ContractError::UnexpectDealState {});
-            }
+    if &deal.state == &DealState::PendCustomerDeposit || &deal.state == &DealState::PendCustomerBankTransfer {
+        if &deal.customer.clone() != &info.sender && &deal.dealer != &info.sender {
+            return Err(ContractError::Unauthorized {});
+        }
 
-            // deal_post_id = deal.post_id.clone();
-            // deal_post_amount = deal.amount.clone();
+        // it also has to be an expired deal for dealer to cancel it
+        // intutively, customer has no action for too long
+        if &deal.expiry.unwrap() > &now {
+            return Err(ContractError::DealNotExpired(deal.expiry.unwrap()));
+        }
 
-            if &deal.state == &DealState::PendCustomerDeposit {
-                if &deal.customer.clone() != &info.sender && &deal.dealer != &info.sender {
-                    return Err(ContractError::Unauthorized {});
-                }
+        deal.state = DealState::CancelAsCustomerMissTransfer;
 
-                // it also has to be an expired deal for dealer to cancel it
-                // intutively, customer has no action for too long
-                if &deal.expiry.unwrap() > &now {
-                    return Err(ContractError::DealNotExpired(deal.expiry.unwrap()));
-                }
+    } else if &deal.state == &DealState::PendDealerBankTransfer {
+        // if get to here it means Customer has paid crypto to the pool
+        // but dealer has not yet execute the wire transfer
+        // only customer is allowed to cancel the deal from here
+        // dealer should have fulfill the obligation
+        if &deal.customer.clone() != &info.sender {
+            return Err(ContractError::Unauthorized {});
+        }
 
-                deal.state = DealState::CancelAsCustomerMissTransfer;
+        // it also has to be an expired deal for customer to cancel it
+        // intutively, dealer has no action for too long
+        if &deal.expiry.unwrap() > &now {
+            return Err(ContractError::DealNotExpired(deal.expiry.unwrap()));
+        }
 
-                // archive deal into past deals
-                past_deals.push(deal.clone());
-                PAST_DEALS.save(deps.storage, &past_deals)?;
+        // Refund to customer
+        messages.push(escrow_transfer(
+            deps.storage,
+            &deal.deal_token,
+            EscrowOwner::Deal(deal_id),
+            &deal.customer,
+            deal.amount
+        )?);
 
-            } else if &deal.state == &DealState::PendCustomerBankTransfer {
-                if &deal.customer.clone() != &info.sender && &deal.dealer != &info.sender {
-                    return Err(ContractError::Unauthorized {});
-                }
+        deal.state = DealState::CancelAsDealerMissTransfer;
+    }
 
-                // it also has to be an expired deal for dealer to cancel it
-                // intutively, customer has no action for too long
-                if &deal.expiry.unwrap() > &now {
-                    return Err(ContractError::DealNotExpired(deal.expiry.unwrap()));
-                }
+    // a customer who let the deal expire has to wait before entering another one
+    if deal.state == DealState::CancelAsCustomerMissTransfer && !config.deal_limits.customer_cool_down.is_zero() {
+        USER_COOL_DOWN.insert(deps.storage, &deal.customer, &(now + config.deal_limits.customer_cool_down))?;
+    }
 
-                deal.state = DealState::CancelAsCustomerMissTransfer;
-
-                let deal_token = deal.deal_token.clone();
-
-                // the deal amount is already deposited by dealer, need to refund it
-                // Refund to dealer
-                cosmos_msg = Some(transfer_msg(
-                    deal.dealer.clone().into_string(),
-                    deal.amount,
-                    None,
-                    None, 
-                    RESPONSE_BLOCK_SIZE,
-                    deal_token.code_hash,
-                    deal_token.address.into_string()
-                )?);
-
-                // archive deal into past deals
-                past_deals.push(deal.clone());
-                PAST_DEALS.save(deps.storage, &past_deals)?;
-
-            } else if &deal.state == &DealState::PendDealerBankTransfer {
-                // if get to here it means Customer has paid crypto to the pool
-                // but dealer has not yet execute the wire transfer
-                // only customer is allowed to cancel the deal from here
-                // dealer should have fulfill the obligation
-                if &deal.customer.clone() != &info.sender {
-                    return Err(ContractError::Unauthorized {});
-                }
+    // archive deal into past deals
+    archive_deal(deps.storage, &deal)?;
 
-                // it also has to be an expired deal for customer to cancel it
-                // intutively, dealer has no action for too long
-                if &deal.expiry.unwrap() > &now {
-                    return Err(ContractError::DealNotExpired(deal.expiry.unwrap()));
-                }
+    // the amount taken from the post goes back to the post or the dealer
+    if let Some(refund_msg) = return_deal_amount(deps.storage, &config.deal_cancel_policy, &deal)? {
+        messages.push(refund_msg);
+    }
 
-                let deal_token = deal.deal_token.clone();
+    Ok(Response::new()
+        .add_messages(messages)
+        .set_data(to_binary(&ExecuteAnswer::DealStageProcess {
+            status: ResponseStatus::Success,
+            deal_id: deal_id
+        })?)
+    )
+}
 
-                // Refund to customer
-                cosmos_msg = Some(transfer_msg(
-                    deal.customer.clone().into_string(),
-                    deal.amount,
-                    None,
-                    None, 
-                    RESPONSE_BLOCK_SIZE,
-                    deal_token.code_hash,
-                    deal_token.address.into_string()
-                )?);
+// Give back the amount a cancelled deal took from its post.
+// The post may have been cancelled, expired or emptied and removed since the deal was entered,
+// in which case the dealer deposit is refunded instead
+fn return_deal_amount(
+    storage: &mut dyn Storage,
+    policy: &DealCancelPolicy,
+    deal: &Deal,
+) -> Result<Option<CosmosMsg>, ContractError> {
+    if policy == &DealCancelPolicy::RestoreToPost && ACTIVE_POST_IDS.contains(storage, &deal.post_id) {
+        let mut post = load_active_post(storage, &deal.post_id)?;
+
+        if post.state == PostState::Open {
+            // only sell posts hold a dealer deposit, dealer_deposit is also set on
+            // dealer buy deals once the dealer confirms the bank transfer
+            if !deal.is_dealer_buy {
+                escrow_move(storage, &deal.deal_token, EscrowOwner::Deal(deal.deal_id), EscrowOwner::Post(post.post_id), deal.amount)?;
+            }
 
-                deal.state = DealState::CancelAsDealerMissTransfer;
+            post.amount += deal.amount;
+            save_active_post(storage, &post)?;
+            return Ok(None);
+        }
+    }
 
-                // archive deal into past deals
-                past_deals.push(deal.clone());
-                PAST_DEALS.save(deps.storage, &past_deals)?;
+    // a dealer buy post holds no deposit, its unfilled amount is simply dropped
+    if deal.is_dealer_buy {
+        return Ok(None);
+    }
 
-            } else if &deal.state == &DealState::Dispute {
-                // Dispute + cancel = refund to the customer/dealer
+    Ok(Some(escrow_transfer(
+        storage,
+        &deal.deal_token,
+        EscrowOwner::Deal(deal.deal_id),
+        &deal.dealer,
+        deal.amount
+    )?))
+}
 
-                if !curr_mods.contains(&info.sender.clone()) {
-                    return Err(ContractError::Unauthorized {});
-                }
+// Transfer tokens out of the contract, debited from the escrow of `owner`
+fn escrow_transfer(
+    storage: &mut dyn Storage,
+    token: &Contract,
+    owner: EscrowOwner,
+    recipient: &Addr,
+    amount: Uint128,
+) -> Result<CosmosMsg, ContractError> {
+    escrow_debit(storage, token, owner, amount)?;
 
-                // refund
-                let recipient = if deal.is_dealer_buy {
-                    deal.customer.clone().into_string()
-                } else { deal.dealer.clone().into_string()};
-
-                let deal_token = deal.deal_token.clone();
-
-                cosmos_msg =  Some(transfer_msg(
-                    recipient,
-                    deal.amount,
-                    None,
-                    None, 
-                    RESPONSE_BLOCK_SIZE,
-                    deal_token.code_hash,
-                    deal_token.address.into_string()
-                )?);
-    
-                deal.state = DealState::CancelAsDispute;
-                deal.resolver = Some(info.sender.clone());
+    Ok(transfer_msg(
+        recipient.to_string(),
+        amount,
+        None,
+        None,
+        RESPONSE_BLOCK_SIZE,
+        token.code_hash.clone(),
+        token.address.to_string()
+    )?)
+}
 
-                // archive deal into past deals
-                past_deals.push(deal.clone());
-                PAST_DEALS.save(deps.storage, &past_deals)?;
-            }
-        },
-        None => {
-            return Err(ContractError::NoMatchingDeal { 0: deal_id });
+pub fn set_status(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    status: ContractStatus,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+
+    // Authorization
+    if let Some(governance) = &config.governance {
+        if info.sender != governance.address {
+            return Err(ContractError::NotGovernanceSender {});
         }
     }
-    // remove the deal
-    active_deals.retain(|x| x.deal_id != deal_id);
-    ACTIVE_DEALS.save(deps.storage, &active_deals.clone())?;
-
-    // Diable the function to revert the amount back to the post
-    // As dealer can always cancel the post, dangerous to assume the post still exist
-    // Also, locking the post is not ideal as price can change frequently
-    // // top up the deal amount back to the post
-    // let mut active_posts = ACTIVE_POSTS.load(deps.storage)?;
 
-    // match active_posts.iter_mut().find(|x| x.post_id == deal_post_id) {
-    //     Some(post) => {
-    //         let new_post_amount = post.amount + deal_post_amount;
-    //         post.amount = new_post_amount;
-
-    //         ACTIVE_POSTS.save(deps.storage, &active_posts.clone())?;
-    //     },
-    //     None => {
-    //         return Err(ContractError::NoMatchingPost { 0: deal_post_id });
-    //     }
-    // }
+    // If governance doesn't exist, we will check admin
+    let curr_admins = &config.admins;
+    if !curr_admins.contains(&info.sender.clone())  {
+        return Err(ContractError::Unauthorized {});
+    }
 
-    if let Some(cosmos_msg) = cosmos_msg {
-        Ok(Response::new()
-            .add_message(cosmos_msg)
-            .set_data(to_binary(&ExecuteAnswer::DealStageProcess {
-                status: ResponseStatus::Success,
-                deal_id: deal_id
-        })?))
-    } else {
-        Ok(Response::new()
-            .set_data(to_binary(&ExecuteAnswer::DealStageProcess {
-                status: ResponseStatus::Success,
-                deal_id: deal_id
-            })?)
-        )
+    // keep the original time if the status is unchanged, the emergency timelock counts from it
+    let curr_status = CONTRACT_STATUS.load(deps.storage)?;
+    if curr_status.status != status {
+        CONTRACT_STATUS.save(deps.storage, &StatusInfo {
+            status: status.clone(),
+            since: Uint128::new(env.block.time.seconds() as u128),
+        })?;
     }
+
+    Ok(Response::new()
+        .add_attribute("action", "set_status")
+        .add_attribute("status", format!("{:?}", status))
+        .add_attribute("sender", info.sender.as_str())
+        .set_data(to_binary(&ExecuteAnswer::SetStatus {
+            status: ResponseStatus::Success,
+        })?)
+    )
 }
 
+// In the StopAllButRefunds status, the depositor of a post or deal can take the deposit back.
+// Admins can trigger the same refund after the timelock, always to the depositor.
 pub fn emergency_withdraw(
     deps: DepsMut,
-    _env: Env,
+    env: Env,
     info: MessageInfo,
-    deal_id: Uint128,
+    target: EmergencyTarget,
 ) -> Result<Response, ContractError> {
     let config = CONFIG.load(deps.storage)?;
-    let mut active_deals = ACTIVE_DEALS.load(deps.storage)?;
-
-    let curr_admins = config.admins;
+    let now = Uint128::new(env.block.time.seconds() as u128);
 
-    if !curr_admins.contains(&info.sender.clone()) {
-        return Err(ContractError::Unauthorized {});
+    let curr_status = CONTRACT_STATUS.load(deps.storage)?;
+    if curr_status.status != ContractStatus::StopAllButRefunds {
+        return Err(ContractError::NotInEmergencyMode {});
     }
+    let since = curr_status.since;
 
-    let cosmos_msg: CosmosMsg;
-
-    match active_deals.iter_mut().find(|x| x.deal_id == deal_id) {
-        Some(deal) => {
-
-            if (deal.is_dealer_buy) && !deal.customer_deposit {
-                return Err(ContractError::Std(StdError::generic_err("No Amount to withdraw"))); 
+    let (depositor, deal_token, owner, amount) = match &target {
+        EmergencyTarget::Post { post_id } => {
+            let post = load_active_post(deps.storage, post_id)?;
+            if !post.dealer_deposit || post.amount.is_zero() {
+                return Err(ContractError::NothingToWithdraw {});
             }
-
-            if !(deal.is_dealer_buy) && !deal.dealer_deposit {
-                return Err(ContractError::Std(StdError::generic_err("No Amount to withdraw"))); 
+            (post.dealer, post.deal_token, EscrowOwner::Post(*post_id), post.amount)
+        },
+        EmergencyTarget::Deal { deal_id } => {
+            let deal = load_active_deal(deps.storage, deal_id)?;
+            if !deal.is_deposited() {
+                return Err(ContractError::NothingToWithdraw {});
             }
-
-            let deal_token = deal.deal_token.clone();
-
-            cosmos_msg = transfer_msg(
-                info.sender.into_string(),
-                deal.amount,
-                None,
-                None, 
-                RESPONSE_BLOCK_SIZE,
-                deal_token.code_hash,
-                deal_token.address.into_string()
-            )?;
+            (deal.payee().clone(), deal.deal_token.clone(), EscrowOwner::Deal(*deal_id), deal.amount)
         },
-        None => {
-            return Err(ContractError::NoMatchingDeal { 0: deal_id });
-       }
+    };
+
+    if info.sender != depositor {
+        if !config.admins.contains(&info.sender) {
+            return Err(ContractError::Unauthorized {});
+        }
+
+        let unlock = since + EMERGENCY_TIMELOCK;
+        if now < unlock {
+            return Err(ContractError::EmergencyTimelock(unlock));
+        }
     }
 
-    // remove deal
-    active_deals.retain(|x| x.deal_id != deal_id);
-    ACTIVE_DEALS.save(deps.storage, &active_deals)?;
+    let cosmos_msg = escrow_transfer(deps.storage, &deal_token, owner, &depositor, amount)?;
 
-    Ok(Response::new().add_message(cosmos_msg))
+    let target_attr = match target {
+        EmergencyTarget::Post { post_id } => {
+            remove_post(deps.storage, &post_id)?;
+            format!("post:{}", post_id)
+        },
+        EmergencyTarget::Deal { deal_id } => {
+            let mut deal = load_active_deal(deps.storage, &deal_id)?;
+            deal.state = DealState::EmergencyRefund;
+            deal.resolver = Some(info.sender.clone());
+            archive_deal(deps.storage, &deal)?;
+            format!("deal:{}", deal_id)
+        },
+    };
+
+    Ok(Response::new()
+        .add_message(cosmos_msg)
+        .add_attribute("action", "emergency_withdraw")
+        .add_attribute("target", target_attr)
+        .add_attribute("recipient", depositor.as_str())
+        .add_attribute("amount", amount.to_string())
+        .add_attribute("sender", info.sender.as_str())
+        .set_data(to_binary(&ExecuteAnswer::EmergencyWithdraw {
+            status: ResponseStatus::Success,
+            amount,
+        })?)
+    )
 }
 
-pub fn admin_delete_deal(
+pub fn admin_close_deal(
     deps: DepsMut,
     _env: Env,
     info: MessageInfo,
     deal_id: Uint128,
+    settlement: DealSettlement,
+    reason: String,
 ) -> Result<Response, ContractError> {
-    let mut active_deals = ACTIVE_DEALS.load(deps.storage)?;
     let config = CONFIG.load(deps.storage)?;
     let curr_admins = config.admins.clone();
 
@@ -1103,17 +1895,57 @@ pub fn admin_delete_deal(
         return Err(ContractError::Unauthorized {});
     }
 
-    match active_deals.iter_mut().find(|x| x.deal_id == deal_id) {
-        Some(deal) => {
-            active_deals.retain(|x| x.deal_id != deal_id);
-            ACTIVE_DEALS.save(deps.storage, &active_deals.clone())?;   
+    if reason.is_empty() || reason.chars().count() > MAX_CLOSE_REASON_LENGTH {
+        return Err(ContractError::InvalidCloseReason(MAX_CLOSE_REASON_LENGTH));
+    }
+
+    let mut deal = load_active_deal(deps.storage, &deal_id)?;
+
+    // no commission is taken, the deal did not complete
+    let counterparty_amount = match settlement {
+        DealSettlement::RefundDepositor => Uint128::zero(),
+        DealSettlement::PayCounterparty => deal.amount,
+        DealSettlement::Split { counterparty_bps } => {
+            if counterparty_bps > COMMISSION_MULTIPLIER {
+                return Err(ContractError::InvalidSplit(counterparty_bps));
+            }
+            deal.amount.multiply_ratio(counterparty_bps, COMMISSION_MULTIPLIER)
         },
-        None => {
-            return Err(ContractError::NoMatchingDeal { 0: deal_id });
-       }
+    };
+    let depositor_amount = deal.amount - counterparty_amount;
+
+    // nothing to settle if the crypto was never deposited
+    let mut messages: Vec<CosmosMsg> = Vec::new();
+    if deal.is_deposited() {
+        if !depositor_amount.is_zero() {
+            messages.push(escrow_transfer(
+                deps.storage,
+                &deal.deal_token,
+                EscrowOwner::Deal(deal_id),
+                &deal.payee().clone(),
+                depositor_amount
+            )?);
+        }
+
+        if !counterparty_amount.is_zero() {
+            messages.push(escrow_transfer(
+                deps.storage,
+                &deal.deal_token,
+                EscrowOwner::Deal(deal_id),
+                &deal.payer().clone(),
+                counterparty_amount
+            )?);
+        }
     }
 
+    deal.state = DealState::AdminClosed;
+    deal.resolver = Some(info.sender.clone());
+    deal.close_reason = Some(reason);
+
+    archive_deal(deps.storage, &deal)?;
+
     Ok(Response::new()
+       .add_messages(messages)
        .set_data(to_binary(&ExecuteAnswer::DealStageProcess {
            status: ResponseStatus::Success,
            deal_id: deal_id
@@ -1125,9 +1957,9 @@ pub fn get_commission(
     deps: DepsMut,
     _env: Env,
     info: MessageInfo,
+    token: Option<String>,
 ) -> Result<Response, ContractError> {
     let config = CONFIG.load(deps.storage)?;
-    let mut total_revenue = TOKEN_REVENUE.load(deps.storage)?;
 
     let curr_admins = config.admins;
 
@@ -1135,18 +1967,181 @@ pub fn get_commission(
         return Err(ContractError::Unauthorized {});
     }
 
-    let cosmos_msg = transfer_msg(
-        info.sender.into_string(),
-        total_revenue,
-        None,
-        None, 
-        RESPONSE_BLOCK_SIZE,
-        config.deal_token_a.code_hash,
-        config.deal_token_a.address.into_string()
-    )?;
+    // revenue is kept even after a token is removed from the whitelist,
+    // so it is looked up in the revenue store instead of DEAL_TOKENS
+    let revenues = match token {
+        Some(token) => {
+            let token_addr = deps.api.addr_validate(&token)?;
+            let revenue = TOKEN_REVENUE.get(deps.storage, &token_addr)
+                .ok_or(ContractError::InvalidDealToken {})?;
+            vec![revenue]
+        },
+        None => TOKEN_REVENUE.iter(deps.storage)?
+            .map(|x| x.map(|(_, revenue)| revenue))
+            .collect::<StdResult<Vec<_>>>()?,
+    };
 
-    total_revenue = Uint128::zero();
-    TOKEN_REVENUE.save(deps.storage, &total_revenue.clone())?;
+    let recipient = config.treasury.unwrap_or(info.sender);
 
-    Ok(Response::new().add_message(cosmos_msg))
-}
\ No newline at end of file
+    let mut messages = vec![];
+    for mut revenue in revenues {
+        if revenue.amount.is_zero() {
+            continue;
+        }
+
+        messages.push(escrow_transfer(
+            deps.storage,
+            &revenue.token,
+            EscrowOwner::Revenue,
+            &recipient,
+            revenue.amount
+        )?);
+
+        revenue.amount = Uint128::zero();
+        TOKEN_REVENUE.insert(deps.storage, &revenue.token.address, &revenue)?;
+    }
+
+    Ok(Response::new().add_messages(messages))
+}
+#[cfg(test)]
+mod tests {
+    use cosmwasm_std::testing::{mock_dependencies, mock_env, mock_info};
+    use cosmwasm_std::{Timestamp, WasmMsg};
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::state::testing::{mock_config, mock_deal, save_mock_state};
+    use crate::state::{ACTIVE_DEAL_IDS, DEALS, ESCROW};
+
+    const DEAL_ID: Uint128 = Uint128::new(1);
+    const AMOUNT: Uint128 = Uint128::new(1_000_000);
+    const EXPIRY: u64 = 1_000_000;
+
+    // the part of a SNIP-20 transfer the tests check
+    #[derive(Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    enum Snip20Msg {
+        Transfer { recipient: String, amount: Uint128 },
+    }
+
+    // a disputed deal holding the crypto deposit, the post it was taken from is gone.
+    // dealer_deposit is also set on a dealer buy deal once the dealer confirms the bank transfer.
+    fn setup(storage: &mut dyn Storage, is_dealer_buy: bool, outcome: DisputeTimeoutOutcome) {
+        let deal = Deal {
+            deal_id: DEAL_ID,
+            is_dealer_buy,
+            amount: AMOUNT,
+            customer_deposit: is_dealer_buy,
+            state: DealState::Dispute,
+            expiry: Some(Uint128::from(EXPIRY)),
+            ..mock_deal()
+        };
+        let config = Config {
+            dispute_timeout_outcome: outcome,
+            ..mock_config()
+        };
+
+        save_mock_state(storage, &config, &deal);
+        escrow_credit(storage, &deal.deal_token, EscrowOwner::Deal(DEAL_ID), AMOUNT).unwrap();
+    }
+
+    fn expire(deps: DepsMut, sender: &str, time: u64) -> Result<Response, ContractError> {
+        let mut env = mock_env();
+        env.block.time = Timestamp::from_seconds(time);
+        expire_dispute(deps, env, mock_info(sender, &[]), DEAL_ID)
+    }
+
+    fn transfers(res: &Response) -> Vec<(String, Uint128)> {
+        res.messages.iter().map(|sub| match &sub.msg {
+            CosmosMsg::Wasm(WasmMsg::Execute { contract_addr, msg, .. }) => {
+                assert_eq!(contract_addr, mock_deal().deal_token.address.as_str());
+                match from_binary(msg).unwrap() {
+                    Snip20Msg::Transfer { recipient, amount } => (recipient, amount),
+                }
+            },
+            _ => panic!("unexpected message"),
+        }).collect()
+    }
+
+    fn deal_escrow(storage: &dyn Storage) -> Uint128 {
+        ESCROW.get(storage, &(mock_deal().deal_token.address, EscrowOwner::Deal(DEAL_ID))).unwrap_or_default()
+    }
+
+    #[test]
+    fn expire_dispute_before_expiry_is_rejected() {
+        for is_dealer_buy in [true, false] {
+            for outcome in [DisputeTimeoutOutcome::RefundDepositor, DisputeTimeoutOutcome::EscalateToAdmins] {
+                let mut deps = mock_dependencies();
+                setup(deps.as_mut().storage, is_dealer_buy, outcome);
+
+                let err = expire(deps.as_mut(), "customer", EXPIRY - 1).unwrap_err();
+                assert!(matches!(err, ContractError::DealNotExpired(expiry) if expiry == Uint128::from(EXPIRY)));
+                assert_eq!(deal_escrow(deps.as_ref().storage), AMOUNT);
+            }
+        }
+    }
+
+    #[test]
+    fn expire_dispute_by_non_party_is_rejected() {
+        for is_dealer_buy in [true, false] {
+            for outcome in [DisputeTimeoutOutcome::RefundDepositor, DisputeTimeoutOutcome::EscalateToAdmins] {
+                let mut deps = mock_dependencies();
+                setup(deps.as_mut().storage, is_dealer_buy, outcome);
+
+                for sender in ["moderator", "admin", "stranger"] {
+                    let err = expire(deps.as_mut(), sender, EXPIRY).unwrap_err();
+                    assert!(matches!(err, ContractError::Unauthorized {}));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn expire_dispute_refunds_customer_of_dealer_buy_deal() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut().storage, true, DisputeTimeoutOutcome::RefundDepositor);
+
+        let res = expire(deps.as_mut(), "dealer", EXPIRY).unwrap();
+        assert_eq!(transfers(&res), vec![("customer".to_string(), AMOUNT)]);
+        assert_eq!(deal_escrow(deps.as_ref().storage), Uint128::zero());
+
+        let deal = DEALS.get(deps.as_ref().storage, &DEAL_ID).unwrap();
+        assert_eq!(deal.state, DealState::CancelAsDispute);
+        assert_eq!(deal.resolver, Some(Addr::unchecked("dealer")));
+        assert!(!ACTIVE_DEAL_IDS.contains(deps.as_ref().storage, &DEAL_ID));
+    }
+
+    #[test]
+    fn expire_dispute_refunds_dealer_of_dealer_sell_deal() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut().storage, false, DisputeTimeoutOutcome::RefundDepositor);
+
+        let res = expire(deps.as_mut(), "customer", EXPIRY).unwrap();
+        assert_eq!(transfers(&res), vec![("dealer".to_string(), AMOUNT)]);
+        assert_eq!(deal_escrow(deps.as_ref().storage), Uint128::zero());
+
+        let deal = DEALS.get(deps.as_ref().storage, &DEAL_ID).unwrap();
+        assert_eq!(deal.state, DealState::CancelAsDispute);
+        assert_eq!(deal.resolver, Some(Addr::unchecked("customer")));
+        assert!(!ACTIVE_DEAL_IDS.contains(deps.as_ref().storage, &DEAL_ID));
+    }
+
+    #[test]
+    fn expire_dispute_escalates_once() {
+        for is_dealer_buy in [true, false] {
+            let mut deps = mock_dependencies();
+            setup(deps.as_mut().storage, is_dealer_buy, DisputeTimeoutOutcome::EscalateToAdmins);
+
+            let res = expire(deps.as_mut(), "customer", EXPIRY).unwrap();
+            assert!(res.messages.is_empty());
+            assert_eq!(deal_escrow(deps.as_ref().storage), AMOUNT);
+
+            let deal = load_active_deal(deps.as_ref().storage, &DEAL_ID).unwrap();
+            assert_eq!(deal.state, DealState::Dispute);
+            assert!(deal.escalated);
+
+            let err = expire(deps.as_mut(), "dealer", EXPIRY + 1).unwrap_err();
+            assert!(matches!(err, ContractError::AlreadyEscalated));
+        }
+    }
+}
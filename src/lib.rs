@@ -3,4 +3,6 @@ pub mod msg;
 pub mod state;
 pub mod asset;
 pub mod execute;
-pub mod query;
\ No newline at end of file
+pub mod query;
+pub mod migrate;
+pub mod validation;
\ No newline at end of file
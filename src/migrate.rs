@@ -0,0 +1,315 @@
+use cosmwasm_schema::cw_serde;
+use cosmwasm_std::{Addr, StdError, StdResult, Storage, Uint128};
+
+use secret_toolkit::storage::Item;
+use secret_toolkit::serialization::Json;
+
+use crate::asset::{Contract, ContractError, ContractStatus, DealCancelPolicy, DealLimits, DealState, DisputeTimeoutOutcome, EscrowOwner, PostState};
+use crate::msg::MigrateMsg;
+use crate::validation::normalize_currency;
+use crate::state::{add_revenue, archive_deal, escrow_credit, save_active_deal, save_active_post, Config, ContractInfo, Currency, Deal, DealToken, Post, StatusInfo, CONFIG, CONTRACT_INFO, CONTRACT_STATUS, CURRENCIES, DEAL_TOKENS};
+
+pub const CONTRACT_NAME: &str = env!("CARGO_PKG_NAME");
+pub const CONTRACT_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+// deployments before contract versioning was introduced
+const UNVERSIONED: &str = "0.1.0";
+
+// number of 0.1.0 past deals already moved by MigratePastDeals
+const LEGACY_PAST_DEALS_MOVED: Item<u32> = Item::new(b"legacy_past_deals_moved");
+
+// state transform of a contract version
+type Migration = fn(&mut dyn Storage, &MigrateMsg) -> Result<(), ContractError>;
+
+// one transform per contract version, run in order
+const MIGRATIONS: [(&str, Migration); 1] = [
+    ("0.2.0", migrate_v0_2_0),
+];
+
+pub fn set_contract_version(storage: &mut dyn Storage) -> StdResult<()> {
+    CONTRACT_INFO.save(storage, &ContractInfo {
+        contract: CONTRACT_NAME.to_string(),
+        version: CONTRACT_VERSION.to_string(),
+    })
+}
+
+// Run every transform between the stored version and the version of this code
+pub fn migrate_state(storage: &mut dyn Storage, msg: &MigrateMsg) -> Result<ContractInfo, ContractError> {
+    let stored = CONTRACT_INFO.may_load(storage)?.unwrap_or(ContractInfo {
+        contract: CONTRACT_NAME.to_string(),
+        version: UNVERSIONED.to_string(),
+    });
+
+    if stored.contract != CONTRACT_NAME {
+        return Err(ContractError::MismatchContractName(stored.contract));
+    }
+
+    let from = parse_version(&stored.version)?;
+    let to = parse_version(CONTRACT_VERSION)?;
+
+    if from > to {
+        return Err(ContractError::CannotDowngrade(stored.version, CONTRACT_VERSION.to_string()));
+    }
+
+    for (version, transform) in MIGRATIONS.iter() {
+        let step = parse_version(version)?;
+        if step > from && step <= to {
+            transform(storage, msg)?;
+        }
+    }
+
+    set_contract_version(storage)?;
+
+    Ok(stored)
+}
+
+fn parse_version(version: &str) -> StdResult<(u64, u64, u64)> {
+    let parts: Vec<u64> = version.split('.')
+        .map(|x| x.parse::<u64>())
+        .collect::<Result<Vec<u64>, _>>()
+        .map_err(|_| StdError::generic_err(format!("Invalid contract version {}", version)))?;
+
+    if parts.len() != 3 {
+        return Err(StdError::generic_err(format!("Invalid contract version {}", version)));
+    }
+
+    Ok((parts[0], parts[1], parts[2]))
+}
+
+// refuse to run a transform against state that is already newer than it
+fn ensure_upgrade(storage: &dyn Storage, target: &str) -> Result<(), ContractError> {
+    if let Some(stored) = CONTRACT_INFO.may_load(storage)? {
+        if parse_version(&stored.version)? >= parse_version(target)? {
+            return Err(ContractError::CannotDowngrade(stored.version, target.to_string()));
+        }
+    }
+    Ok(())
+}
+
+// 0.1.0 -> 0.2.0
+// posts and deals move from single Item vectors to the keyed storage
+fn migrate_v0_2_0(storage: &mut dyn Storage, msg: &MigrateMsg) -> Result<(), ContractError> {
+    ensure_upgrade(storage, "0.2.0")?;
+
+    let config = v0_1::CONFIG.load(storage)?;
+
+    // the fixed deal_token_a/b/c become entries of the token whitelist,
+    // symbol and limits are unknown and can be set afterwards with AddDealToken
+    for contract in [&config.deal_token_a, &config.deal_token_b, &config.deal_token_c] {
+        DEAL_TOKENS.insert(storage, &contract.address, &DealToken {
+            contract: contract.clone(),
+            symbol: String::new(),
+            decimals: 6,
+            min_post_amount: Uint128::zero(),
+            max_post_amount: None,
+        })?;
+    }
+
+    // the single revenue counter added up the commission of every deal token,
+    // the split isn't stored so it has to come with the migrate message
+    let legacy_revenue = v0_1::TOKEN_REVENUE.may_load(storage)?.unwrap_or_default();
+    let split_total: Uint128 = msg.legacy_revenue.iter().map(|x| x.amount).sum();
+    if split_total != legacy_revenue {
+        return Err(ContractError::LegacyRevenueMismatch(split_total, legacy_revenue));
+    }
+
+    for revenue in msg.legacy_revenue.iter() {
+        let token = [&config.deal_token_a, &config.deal_token_b, &config.deal_token_c].into_iter()
+            .find(|x| x.address.as_str() == revenue.token)
+            .ok_or(ContractError::InvalidDealToken {})?;
+
+        if !revenue.amount.is_zero() {
+            add_revenue(storage, token, revenue.amount)?;
+            escrow_credit(storage, token, EscrowOwner::Revenue, revenue.amount)?;
+        }
+    }
+    v0_1::TOKEN_REVENUE.remove(storage);
+
+    CONFIG.save(storage, &config.into())?;
+
+    // the currencies that add_post used to allow, before the check was commented out
+    for code in ["HKD", "USD", "EUR"] {
+        CURRENCIES.insert(storage, &code.to_string(), &Currency {
+            code: code.to_string(),
+            decimals: 2,
+            enabled: true,
+        })?;
+    }
+
+    CONTRACT_STATUS.save(storage, &StatusInfo {
+        status: ContractStatus::Normal,
+        since: Uint128::zero(),
+    })?;
+
+    if let Some(posts) = v0_1::ACTIVE_POSTS.may_load(storage)? {
+        for post in posts {
+            let post: Post = post.into();
+            save_active_post(storage, &post)?;
+
+            // seed the escrow ledger with the deposits already held
+            if post.dealer_deposit {
+                escrow_credit(storage, &post.deal_token, EscrowOwner::Post(post.post_id), post.amount)?;
+            }
+        }
+        v0_1::ACTIVE_POSTS.remove(storage);
+    }
+
+    if let Some(deals) = v0_1::ACTIVE_DEALS.may_load(storage)? {
+        for deal in deals {
+            let deal: Deal = deal.into();
+            save_active_deal(storage, &deal)?;
+
+            if deal.is_deposited() {
+                escrow_credit(storage, &deal.deal_token, EscrowOwner::Deal(deal.deal_id), deal.amount)?;
+            }
+        }
+        v0_1::ACTIVE_DEALS.remove(storage);
+    }
+
+    // past deals are left for MigratePastDeals, moving all of them here could
+    // run out of gas and block the upgrade
+
+    Ok(())
+}
+
+// Move the next `limit` past deals of 0.1.0 to the keyed storage,
+// returns the number of deals moved and the number still left
+pub fn migrate_past_deals(storage: &mut dyn Storage, limit: u32) -> Result<(u32, u32), ContractError> {
+    let deals = match v0_1::PAST_DEALS.may_load(storage)? {
+        Some(deals) => deals,
+        None => return Ok((0, 0)),
+    };
+
+    let start = LEGACY_PAST_DEALS_MOVED.may_load(storage)?.unwrap_or_default() as usize;
+    let end = start.saturating_add(limit as usize).min(deals.len());
+    let total = deals.len();
+
+    for deal in deals.into_iter().skip(start).take(end - start) {
+        archive_deal(storage, &deal.into())?;
+    }
+
+    if end == total {
+        v0_1::PAST_DEALS.remove(storage);
+        LEGACY_PAST_DEALS_MOVED.remove(storage);
+    } else {
+        LEGACY_PAST_DEALS_MOVED.save(storage, &(end as u32))?;
+    }
+
+    Ok(((end - start) as u32, (total - end) as u32))
+}
+
+// state layout of 0.1.0, only read when migrating
+mod v0_1 {
+    use super::*;
+
+    pub const CONFIG: Item<Config, Json> = Item::new(b"config");
+    pub const PAST_DEALS: Item<Vec<Deal>, Json> = Item::new(b"deals");
+    pub const ACTIVE_POSTS: Item<Vec<Post>, Json> = Item::new(b"active_posts");
+    pub const ACTIVE_DEALS: Item<Vec<Deal>, Json> = Item::new(b"active_deals");
+    pub const TOKEN_REVENUE: Item<Uint128> = Item::new(b"token_revenue");
+
+    #[cw_serde]
+    pub struct Config {
+        pub admins: Vec<Addr>,
+        pub deal_commission: Uint128,
+        pub deal_token_a: Contract,
+        pub deal_token_b: Contract,
+        pub deal_token_c: Contract,
+        pub query_auth: Contract,
+        pub governance: Option<Contract>,
+    }
+
+    #[cw_serde]
+    pub struct Post {
+        pub post_id: Uint128,
+        pub is_dealer_buy: bool,
+        pub deal_token: Contract,
+        pub amount: Uint128,
+        pub min_amount: Uint128,
+        pub settle_currency: String,
+        pub settle_price: Uint128,
+        pub dealer_deposit: bool,
+        pub dealer: Addr,
+        pub state: PostState,
+        pub expiry: Uint128,
+    }
+
+    #[cw_serde]
+    pub struct Deal {
+        pub deal_id: Uint128,
+        pub post_id: Uint128,
+        pub is_dealer_buy: bool,
+        pub deal_token: Contract,
+        pub amount: Uint128,
+        pub settle_currency: String,
+        pub settle_price: Uint128,
+        pub dealer_deposit: bool,
+        pub customer_deposit: bool,
+        pub dealer: Addr,
+        pub customer: Addr,
+        pub state: DealState,
+        pub resolver: Option<Addr>,
+        pub expiry: Option<Uint128>,
+    }
+}
+
+impl From<v0_1::Config> for Config {
+    fn from(item: v0_1::Config) -> Self {
+        Config {
+            admins: item.admins,
+            deal_commission: item.deal_commission,
+            query_auth: item.query_auth,
+            governance: item.governance,
+            treasury: None,
+            deal_cancel_policy: DealCancelPolicy::default(),
+            deal_limits: DealLimits::default(),
+            dispute_quorum: 1,
+            dispute_timeout_outcome: DisputeTimeoutOutcome::default(),
+        }
+    }
+}
+
+impl From<v0_1::Post> for Post {
+    fn from(item: v0_1::Post) -> Self {
+        Post {
+            post_id: item.post_id,
+            is_dealer_buy: item.is_dealer_buy,
+            deal_token: item.deal_token,
+            amount: item.amount,
+            min_amount: item.min_amount,
+            max_amount: None,
+            settle_currency: normalize_currency(&item.settle_currency),
+            settle_price: item.settle_price,
+            dealer_deposit: item.dealer_deposit,
+            dealer: item.dealer,
+            state: item.state,
+            expiry: item.expiry,
+        }
+    }
+}
+
+impl From<v0_1::Deal> for Deal {
+    fn from(item: v0_1::Deal) -> Self {
+        Deal {
+            deal_id: item.deal_id,
+            post_id: item.post_id,
+            is_dealer_buy: item.is_dealer_buy,
+            deal_token: item.deal_token,
+            amount: item.amount,
+            settle_currency: normalize_currency(&item.settle_currency),
+            settle_price: item.settle_price,
+            dealer_deposit: item.dealer_deposit,
+            customer_deposit: item.customer_deposit,
+            dealer: item.dealer,
+            customer: item.customer,
+            state: item.state,
+            resolver: item.resolver,
+            expiry: item.expiry,
+            close_reason: None,
+            votes: vec![],
+            assigned_moderator: None,
+            assignment_expiry: None,
+            escalated: false,
+        }
+    }
+}
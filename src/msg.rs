@@ -1,20 +1,47 @@
 use cosmwasm_schema::cw_serde;
 use cosmwasm_std::{Addr, Binary, Uint128};
 use schemars::JsonSchema;
+use secret_toolkit::permit::Permit;
 use serde::{Deserialize, Serialize};
 
-use crate::asset::RawContract;
-use crate::state::{Config, Deal, PaymentInfo, Post};
+use crate::asset::{ContractStatus, DealCancelPolicy, DealLimits, DealSettlement, DealState, DisputeDecision, DisputeTimeoutOutcome, EmergencyTarget, PostState, RawContract};
+use crate::state::{Config, Currency, Deal, DealToken, Evidence, PaymentInfo, Post, TokenRevenue};
 
 
 #[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq, JsonSchema)]
 pub struct InstantiateMsg {
     pub admins: Vec<String>,
     pub deal_commission: Uint128,  // in number of bps, 1 = 0.01% of the amount of the deal to be commission
-    pub deal_token_a: RawContract,
-    pub deal_token_b: RawContract,
-    pub deal_token_c: RawContract,
+    pub deal_tokens: Vec<DealTokenMsg>,
+    pub currencies: Vec<Currency>,
     pub query_auth: RawContract,
+    pub treasury: Option<String>,
+    pub deal_cancel_policy: Option<DealCancelPolicy>,  // RestoreToPost if None
+    pub deal_limits: Option<DealLimits>,  // no limits if None
+    pub dispute_quorum: Option<u32>,  // 1 if None
+    pub dispute_timeout_outcome: Option<DisputeTimeoutOutcome>,  // RefundDepositor if None
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq, JsonSchema)]
+pub struct DealTokenMsg {
+    pub token: RawContract,
+    pub symbol: String,
+    pub decimals: u8,
+    pub min_post_amount: Uint128,
+    pub max_post_amount: Option<Uint128>,
+}
+
+#[cw_serde]
+pub struct MigrateMsg {
+    // upgrading from 0.1.0, the single commission counter split by deal token
+    #[serde(default)]
+    pub legacy_revenue: Vec<LegacyRevenue>,
+}
+
+#[cw_serde]
+pub struct LegacyRevenue {
+    pub token: String,  // address of deal_token_a, deal_token_b or deal_token_c
+    pub amount: Uint128,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq, JsonSchema)]
@@ -25,11 +52,18 @@ pub enum ExecuteMsg {
         deal_commission: Option<Uint128>,
         query_auth: Option<RawContract>,
         governance: Option<RawContract>,
+        treasury: Option<String>,
+        deal_cancel_policy: Option<DealCancelPolicy>,
+        deal_limits: Option<DealLimits>,
+        dispute_quorum: Option<u32>,
+        dispute_timeout_outcome: Option<DisputeTimeoutOutcome>,
     },
-    UpdateDealToken {
-        deal_token_a: RawContract,
-        deal_token_b: RawContract,
-        deal_token_c: RawContract,
+    // add a token to the whitelist, or update the metadata of a listed token
+    AddDealToken {
+        token: DealTokenMsg,
+    },
+    RemoveDealToken {
+        token: String,  // address of the token
     },
     AddModerator {
         mod_addr: String,
@@ -37,6 +71,8 @@ pub enum ExecuteMsg {
     RemoveModerator {
         mod_addr: String,
     },
+    // add a currency to the registry, or update it
+    SetCurrency { currency: Currency },
     RegisterPaymentInfo {
         method: String,
         detail: String
@@ -53,10 +89,35 @@ pub enum ExecuteMsg {
         deal_token: RawContract,  // which token are we trading?
         amount: Uint128,  // amount of snip 20 token, e.g. 1_000_000 = 1
         min_amount: Uint128, // min amount required by dealer to open a deal
+        max_amount: Option<Uint128>, // max amount of a single deal, no cap if None
         settle_currency: String,
         settle_price: Uint128,  // price, also expressed as 1_000_000 = 1, so 50_000 = $0.05
     },
     CancelPost { post_id: Uint128 },
+    // change the terms of a post, deals already entered keep their terms
+    UpdatePost {
+        post_id: Uint128,
+        settle_price: Option<Uint128>,
+        min_amount: Option<Uint128>,
+        max_amount: Option<Uint128>,
+        clear_max_amount: Option<bool>,  // remove the max amount, cannot be used with max_amount
+        amount: Option<Uint128>,  // only before the crypto is deposited
+    },
+    RenewPost { post_id: Uint128 },
+    // take back part of the deposit of an open sell post, all of the remaining amount if None
+    WithdrawFromPost {
+        post_id: Uint128,
+        amount: Option<Uint128>,
+    },
+    // remove expired posts and refund the dealer deposit left in them, anyone can call
+    ExpirePosts {
+        start_after: Option<Uint128>,  // post id to continue from
+        limit: Option<u32>,  // number of post ids checked
+    },
+    // move the next past deals of 0.1.0 to the keyed storage after an upgrade, anyone can call
+    MigratePastDeals {
+        limit: Option<u32>,
+    },
     EnterDeal { 
         post_id: Uint128,
         amount: Uint128,  // amount of snip 20 token that customer wanna trade from the post
@@ -65,9 +126,44 @@ pub enum ExecuteMsg {
     DisputeDeal { deal_id: Uint128 },
     ResolveDeal { deal_id: Uint128 },
     CancelDeal { deal_id: Uint128 },
-    AdminDeleteDeal { deal_id: Uint128 },
-    // EmergencyWithdraw { deal_id: Uint128 },  // only for testing
-    GetCommission {},
+    // evidence for the moderators, only by the customer or dealer of a disputed deal
+    SubmitEvidence {
+        deal_id: Uint128,
+        content_hash: String,
+        note: String,
+    },
+    // hand a dispute to the next moderator once the assigned one missed the deadline, anyone can call
+    ReassignDispute { deal_id: Uint128 },
+    // split a disputed deal between the crypto buyer and the depositor, a Split vote
+    SettleDispute {
+        deal_id: Uint128,
+        to_receiver_bps: Uint128,
+    },
+    // apply the dispute_timeout_outcome to a dispute past its expiry, by the customer or dealer
+    ExpireDispute { deal_id: Uint128 },
+    // moderator vote on a disputed deal, ResolveDeal / CancelDeal on a dispute are the same as Release / Refund
+    CastDisputeVote {
+        deal_id: Uint128,
+        decision: DisputeDecision,
+    },
+    // settle and archive a deal that cannot be completed by its parties
+    AdminCloseDeal {
+        deal_id: Uint128,
+        settlement: DealSettlement,
+        reason: String,
+    },
+    SetStatus { status: ContractStatus },
+    // reclaim a deposit in the StopAllButRefunds status, always paid to the depositor
+    EmergencyWithdraw { target: EmergencyTarget },
+    GetCommission {
+        token: Option<String>,  // address of the token to withdraw, all tokens if None
+    },
+    RevokePermit { permit_name: String },
+    // let this contract read its own balance of a token, for the Solvency query
+    SetTokenViewingKey {
+        token: RawContract,
+        key: String,
+    },
 }
 
 #[cw_serde]
@@ -75,7 +171,10 @@ pub enum ExecuteAnswer {
     UpdateConfig {
         status: ResponseStatus,
     },
-    UpdateDealToken {
+    AddDealToken {
+        status: ResponseStatus,
+    },
+    RemoveDealToken {
         status: ResponseStatus,
     },
     AddModerator {
@@ -84,6 +183,9 @@ pub enum ExecuteAnswer {
     RemoveModerator {
         status: ResponseStatus,
     },
+    SetCurrency {
+        status: ResponseStatus,
+    },
     RegisterPaymentInfo {
         status: ResponseStatus
     },
@@ -103,26 +205,235 @@ pub enum ExecuteAnswer {
         status: ResponseStatus,
         post_id: Uint128
     },
+    UpdatePost {
+        status: ResponseStatus,
+        post_id: Uint128,
+    },
+    WithdrawFromPost {
+        status: ResponseStatus,
+        post_id: Uint128,
+        amount: Uint128,
+    },
+    RenewPost {
+        status: ResponseStatus,
+        post_id: Uint128,
+        expiry: Uint128,
+    },
+    ExpirePosts {
+        status: ResponseStatus,
+        post_ids: Vec<Uint128>,
+        next_start_after: Option<Uint128>,  // None once every post id was checked
+    },
+    MigratePastDeals {
+        status: ResponseStatus,
+        migrated: u32,
+        remaining: u32,  // 0 once every past deal was moved
+    },
     DealStageProcess {
         status: ResponseStatus,
         deal_id: Uint128
+    },
+    SubmitEvidence {
+        status: ResponseStatus,
+        deal_id: Uint128,
+    },
+    ReassignDispute {
+        status: ResponseStatus,
+        deal_id: Uint128,
+        moderator: Option<Addr>,
+    },
+    RevokePermit {
+        status: ResponseStatus,
+    },
+    SetTokenViewingKey {
+        status: ResponseStatus,
+    },
+    SetStatus {
+        status: ResponseStatus,
+    },
+    EmergencyWithdraw {
+        status: ResponseStatus,
+        amount: Uint128,
     }
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq, JsonSchema)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum QueryMsg {
     // GetCount returns the current count as a json-encoded number
     Config {},
-    PastDeals {},
-    ActiveDeals {},
-    ActivePosts {},
+    PastDeals {
+        filter: Option<PublicDealFilter>,
+        start_after: Option<Uint128>,  // deal id
+        limit: Option<u32>,
+        order: Option<SortOrder>,
+    },
+    ActiveDeals {
+        filter: Option<PublicDealFilter>,
+        start_after: Option<Uint128>,  // deal id
+        limit: Option<u32>,
+        order: Option<SortOrder>,
+    },
+    ActivePosts {
+        filter: Option<PostFilter>,
+        start_after: Option<Uint128>,  // post id
+        limit: Option<u32>,
+        order: Option<SortOrder>,
+    },
     MyPosts { key: String, address: String },
     MyDeals { key: String, address: String },
+    // active disputes assigned to a moderator
+    MyAssignedDisputes {
+        key: String,
+        address: String,
+        start_after: Option<Uint128>,
+        limit: Option<u32>,
+        order: Option<SortOrder>,
+    },
     MyPaymentInfo { key: String, address: String },
+    // active deals of the user against the configured limits
+    MyDealAllowance { key: String, address: String },
     DealDetail { key: String, address: String, deal_id: Uint128 },
-    Revenue {},
+    // evidence of a deal, only for its parties and the moderators
+    DisputeEvidence { key: String, address: String, deal_id: Uint128 },
+    // full deal records, all deals for moderators / admins, otherwise only the deals of the user
+    PrivateDeals {
+        key: String,
+        address: String,
+        archived: bool,  // past deals if true, otherwise active deals
+        filter: Option<DealFilter>,
+        start_after: Option<Uint128>,
+        limit: Option<u32>,
+        order: Option<SortOrder>,
+    },
+    Revenue {
+        token: Option<String>,  // all tokens if None
+    },
     Moderators {},
+    DealTokens {},
+    Currencies {},
+    // escrow ledger of each token against the token balance of the contract
+    Solvency {},
+    Status {},
+    // SNIP-24 permit, alternative to the query_auth viewing key
+    WithPermit {
+        permit: Permit<QueryPermission>,
+        query: QueryWithPermit,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum QueryWithPermit {
+    MyPosts {},
+    MyDeals {},
+    MyAssignedDisputes {
+        start_after: Option<Uint128>,
+        limit: Option<u32>,
+        order: Option<SortOrder>,
+    },
+    MyPaymentInfo {},
+    MyDealAllowance {},
+    DealDetail { deal_id: Uint128 },
+    DisputeEvidence { deal_id: Uint128 },
+    PrivateDeals {
+        archived: bool,
+        filter: Option<DealFilter>,
+        start_after: Option<Uint128>,
+        limit: Option<u32>,
+        order: Option<SortOrder>,
+    },
+}
+
+// permissions a user can grant in a query permit
+#[cw_serde]
+pub enum QueryPermission {
+    Owner,  // posts, deals and deal detail of the user
+    PaymentInfo,  // payment info of the user
+}
+
+#[derive(Eq)]
+#[cw_serde]
+pub enum SortOrder {
+    Ascending,
+    Descending,
+}
+
+// every field is optional, unset fields match everything
+#[derive(Default, Eq)]
+#[cw_serde]
+pub struct PostFilter {
+    pub deal_token: Option<String>,  // address of the deal token
+    pub is_dealer_buy: Option<bool>,
+    pub settle_currency: Option<String>,
+    pub min_price: Option<Uint128>,
+    pub max_price: Option<Uint128>,
+    pub state: Option<PostState>,
+    pub include_expired: Option<bool>,  // expired posts are skipped by default
+}
+
+#[derive(Default, Eq)]
+#[cw_serde]
+pub struct DealFilter {
+    pub deal_token: Option<String>,  // address of the deal token
+    pub is_dealer_buy: Option<bool>,
+    pub settle_currency: Option<String>,
+    pub min_price: Option<Uint128>,
+    pub max_price: Option<Uint128>,
+    pub state: Option<DealState>,
+}
+
+// filter of the public deal lists, no price so the redacted price can't be searched for
+#[derive(Default, Eq)]
+#[cw_serde]
+pub struct PublicDealFilter {
+    pub deal_token: Option<String>,  // address of the deal token
+    pub is_dealer_buy: Option<bool>,
+    pub settle_currency: Option<String>,
+    pub state: Option<DealState>,
+}
+
+impl From<PublicDealFilter> for DealFilter {
+    fn from(filter: PublicDealFilter) -> Self {
+        DealFilter {
+            deal_token: filter.deal_token,
+            is_dealer_buy: filter.is_dealer_buy,
+            settle_currency: filter.settle_currency,
+            state: filter.state,
+            ..DealFilter::default()
+        }
+    }
+}
+
+// public view of a deal, without the parties, amount, price or the post,
+// which would give away the dealer and price
+#[cw_serde]
+pub struct DealSummary {
+    pub deal_id: Uint128,
+    pub deal_token: Addr,
+    pub is_dealer_buy: bool,
+    pub settle_currency: String,
+    pub state: DealState,
+}
+
+impl From<Deal> for DealSummary {
+    fn from(deal: Deal) -> Self {
+        DealSummary {
+            deal_id: deal.deal_id,
+            deal_token: deal.deal_token.address,
+            is_dealer_buy: deal.is_dealer_buy,
+            settle_currency: deal.settle_currency,
+            state: deal.state,
+        }
+    }
+}
+
+#[cw_serde]
+pub struct TokenSolvency {
+    pub token: Addr,
+    pub escrow: Uint128,  // sum of the escrow ledger in this token
+    pub balance: Option<Uint128>,  // None if the contract has no working viewing key for the token
+    pub solvent: Option<bool>,  // balance covers the escrow
 }
 
 #[cw_serde]
@@ -141,29 +452,64 @@ pub enum QueryAnswer {
         config: Config,
     },
     PastDeals {
-        past_deals: Vec<Deal>,
+        past_deals: Vec<DealSummary>,
+        next_start_after: Option<Uint128>,  // None when there are no more pages
     },
     ActiveDeals {
+        deals: Vec<DealSummary>,
+        next_start_after: Option<Uint128>,
+    },
+    PrivateDeals {
         deals: Vec<Deal>,
+        next_start_after: Option<Uint128>,
     },
     ActivePosts {
         posts: Vec<Post>,
+        next_start_after: Option<Uint128>,
     },
     MyDeals {
         deals: Vec<Deal>,
     },
+    MyAssignedDisputes {
+        deals: Vec<Deal>,
+        next_start_after: Option<Uint128>,
+    },
     MyPaymentInfo {
         payment_info: PaymentInfo,
     },
+    MyDealAllowance {
+        active_as_customer: u32,
+        active_as_dealer: u32,
+        customer_deals_left: Option<u32>,  // None if there is no limit
+        dealer_deals_left: Option<u32>,
+        cool_down_until: Option<Uint128>,  // set while the user cannot enter deals
+    },
     DealDetail {
         deal: Deal,
-        payment_info: PaymentInfo
+        payment_info: PaymentInfo,  // payee, i.e. the receiver of the bank transfer
+        payer_payment_info: Option<PaymentInfo>,  // only shown to moderators and admins
+    },
+    DisputeEvidence {
+        evidence: Vec<Evidence>,
     },
     Revenue {
-        revenue: Uint128,
+        revenue: Vec<TokenRevenue>,
     },
     Moderators {
         mods: Vec<Addr>,
+    },
+    DealTokens {
+        tokens: Vec<DealToken>,
+    },
+    Currencies {
+        currencies: Vec<Currency>,
+    },
+    Solvency {
+        tokens: Vec<TokenSolvency>,
+    },
+    Status {
+        status: ContractStatus,
+        since: Uint128,
     }
 }
 
@@ -1,7 +1,16 @@
-use cosmwasm_std::{Deps, StdResult};
+use cosmwasm_std::{Addr, Deps, Env, StdError, StdResult, Storage, Uint128};
+use secret_toolkit::serialization::Json;
+use secret_toolkit::snip20::balance_query;
+use secret_toolkit::storage::Keyset;
 
-use crate::{msg::QueryAnswer, state::{ACTIVE_DEALS, ACTIVE_POSTS, CONFIG, MODERATORS, PAST_DEALS, TOKEN_REVENUE}};
+use crate::asset::{DealRole, DealState};
+use crate::contract::RESPONSE_BLOCK_SIZE;
+use crate::validation::normalize_currency;
+use crate::{msg::{DealFilter, DealSummary, PostFilter, PublicDealFilter, QueryAnswer, SortOrder, TokenSolvency}, state::{load_active_deal, load_active_deal_count, load_active_deals, load_active_posts, Deal, PaymentInfo, Post, ACTIVE_DEAL_IDS, ACTIVE_POST_IDS, CONFIG, CONTRACT_STATUS, CURRENCIES, DEALS, DEAL_EVIDENCE, DEAL_TOKENS, ESCROW_TOTALS, MAX_DEAL_ID, MAX_POST_ID, MODERATORS, PAST_DEAL_IDS, POSTS, TOKEN_REVENUE, TOKEN_VIEWING_KEYS, USER_COOL_DOWN, USER_PAYMENT_INFO}};
 
+pub const DEFAULT_LIMIT: u32 = 30;
+pub const MAX_LIMIT: u32 = 100;
+pub const MAX_SCAN: u32 = 500;  // ids checked by one page, whether they match or not
 
 
 pub fn config(deps: Deps) -> StdResult<QueryAnswer> {
@@ -10,38 +19,455 @@ pub fn config(deps: Deps) -> StdResult<QueryAnswer> {
     })
 }
 
-pub fn past_deals(deps: Deps) -> StdResult<QueryAnswer> {
+pub fn past_deals(
+    deps: Deps,
+    filter: Option<PublicDealFilter>,
+    start_after: Option<Uint128>,
+    limit: Option<u32>,
+    order: Option<SortOrder>,
+) -> StdResult<QueryAnswer> {
+    let (past_deals, next_start_after) = page_deals(deps.storage, &PAST_DEAL_IDS, None, filter.map(DealFilter::from), start_after, limit, order)?;
+
     Ok(QueryAnswer::PastDeals {
-        past_deals: PAST_DEALS.load(deps.storage)?,
+        past_deals: past_deals.into_iter().map(DealSummary::from).collect(),
+        next_start_after,
     })
 }
 
-pub fn active_deals(deps: Deps) -> StdResult<QueryAnswer> {
+pub fn active_deals(
+    deps: Deps,
+    filter: Option<PublicDealFilter>,
+    start_after: Option<Uint128>,
+    limit: Option<u32>,
+    order: Option<SortOrder>,
+) -> StdResult<QueryAnswer> {
+    let (deals, next_start_after) = page_deals(deps.storage, &ACTIVE_DEAL_IDS, None, filter.map(DealFilter::from), start_after, limit, order)?;
+
     Ok(QueryAnswer::ActiveDeals {
-        deals: ACTIVE_DEALS.load(deps.storage)?,
+        deals: deals.into_iter().map(DealSummary::from).collect(),
+        next_start_after,
+    })
+}
+
+// Full deal records for an authenticated user.
+// Moderators and admins see every deal, other users only see deals they are a party of.
+pub fn private_deals(
+    deps: Deps,
+    user: Addr,
+    archived: bool,
+    filter: Option<DealFilter>,
+    start_after: Option<Uint128>,
+    limit: Option<u32>,
+    order: Option<SortOrder>,
+) -> StdResult<QueryAnswer> {
+    let config = CONFIG.load(deps.storage)?;
+    let curr_mods = MODERATORS.load(deps.storage)?;
+
+    let party = if config.admins.contains(&user) || curr_mods.contains(&user) {
+        None
+    } else {
+        Some(&user)
+    };
+
+    let ids = if archived { &PAST_DEAL_IDS } else { &ACTIVE_DEAL_IDS };
+
+    let (deals, next_start_after) = page_deals(deps.storage, ids, party, filter, start_after, limit, order)?;
+
+    Ok(QueryAnswer::PrivateDeals {
+        deals,
+        next_start_after,
+    })
+}
+
+pub fn active_posts(
+    deps: Deps,
+    env: Env,
+    filter: Option<PostFilter>,
+    start_after: Option<Uint128>,
+    limit: Option<u32>,
+    order: Option<SortOrder>,
+) -> StdResult<QueryAnswer> {
+    let filter = filter.unwrap_or_default();
+    let now = Uint128::new(env.block.time.seconds() as u128);
+    let max_id = MAX_POST_ID.load(deps.storage)?;
+
+    let (posts, next_start_after) = scan_ids(deps.storage, &ACTIVE_POST_IDS, max_id, start_after, limit, order, |post_id| {
+        POSTS.get(deps.storage, &post_id).filter(|post| post_matches(post, &filter, now))
+    });
+
+    Ok(QueryAnswer::ActivePosts { posts, next_start_after })
+}
+
+// The private queries below take an already authenticated user,
+// either from a query_auth viewing key or from a query permit
+
+pub fn my_posts(deps: Deps, user: Addr) -> StdResult<Vec<Post>> {
+    let active_posts = load_active_posts(deps.storage)?;
+
+    let mut list_posts: Vec<Post> = Vec::new();
+
+    for post in active_posts {
+        if user == post.dealer.clone() {
+            list_posts.push(post.clone());
+        }
+    }
+
+    Ok(list_posts)
+}
+
+pub fn my_deals(deps: Deps, user: Addr) -> StdResult<Vec<Deal>> {
+    let active_deals = load_active_deals(deps.storage)?;
+
+    let mut list_deals: Vec<Deal> = Vec::new();
+
+    for deal in active_deals {
+        let customer = deal.customer.clone();
+        if user == customer || user == deal.dealer.clone() {
+            list_deals.push(deal.clone());
+        }
+    }
+
+    Ok(list_deals)
+}
+
+pub fn my_assigned_disputes(
+    deps: Deps,
+    user: Addr,
+    start_after: Option<Uint128>,
+    limit: Option<u32>,
+    order: Option<SortOrder>,
+) -> StdResult<QueryAnswer> {
+    let max_id = MAX_DEAL_ID.load(deps.storage)?;
+
+    let (deals, next_start_after) = scan_ids(deps.storage, &ACTIVE_DEAL_IDS, max_id, start_after, limit, order, |deal_id| {
+        DEALS.get(deps.storage, &deal_id)
+            .filter(|deal| deal.state == DealState::Dispute && deal.assigned_moderator.as_ref() == Some(&user))
+    });
+
+    Ok(QueryAnswer::MyAssignedDisputes {
+        deals,
+        next_start_after,
     })
 }
 
-pub fn active_posts(deps: Deps) -> StdResult<QueryAnswer> {
-    Ok(QueryAnswer::ActivePosts {
-        posts: ACTIVE_POSTS.load(deps.storage)?,
+pub fn my_payment_info(deps: Deps, user: Addr) -> StdResult<PaymentInfo> {
+    let user_payment_info = USER_PAYMENT_INFO.get(deps.storage, &user);
+
+    if let Some(user_payment_info) = user_payment_info {
+        Ok(user_payment_info)
+    } else {
+        Err(StdError::generic_err("No payment info is found"))
+    }
+}
+
+// Active deals of the user against the deal limits, and the cool down if any
+pub fn my_deal_allowance(deps: Deps, env: Env, user: Addr) -> StdResult<QueryAnswer> {
+    let now = Uint128::new(env.block.time.seconds() as u128);
+    let limits = CONFIG.load(deps.storage)?.deal_limits;
+    let count = load_active_deal_count(deps.storage, &user);
+
+    Ok(QueryAnswer::MyDealAllowance {
+        active_as_customer: count.as_customer,
+        active_as_dealer: count.as_dealer,
+        customer_deals_left: limits.max_customer_deals.map(|x| x.saturating_sub(count.as_customer)),
+        dealer_deals_left: limits.max_dealer_deals.map(|x| x.saturating_sub(count.as_dealer)),
+        cool_down_until: USER_COOL_DOWN.get(deps.storage, &user).filter(|until| until > &now),
     })
 }
 
-pub fn my_deals(deps: Deps) -> StdResult<QueryAnswer> {
-    Ok(QueryAnswer::MyDeals {
-        deals: ACTIVE_DEALS.load(deps.storage)?,
+// Only the parties, moderators and admins can read a deal.
+// The parties see the payment info of the payee, moderators and admins see both parties.
+pub fn deal_detail(deps: Deps, user: Addr, deal_id: Uint128) -> StdResult<QueryAnswer> {
+    let config = CONFIG.load(deps.storage)?;
+    let curr_mods = MODERATORS.load(deps.storage)?;
+
+    let deal = load_active_deal(deps.storage, &deal_id)
+        .map_err(|_| StdError::generic_err("No deal is found"))?;
+
+    let roles = deal.roles_of(&user, &config.admins, &curr_mods);
+
+    if roles.is_empty() {
+        return Err(StdError::generic_err("Unauthorized"));
+    }
+
+    let payment_info = USER_PAYMENT_INFO.get(deps.storage, deal.payee())
+        .ok_or_else(|| StdError::generic_err("No payment info is found"))?;
+
+    let payer_payment_info = if roles.contains(&DealRole::Admin) || roles.contains(&DealRole::Moderator) {
+        USER_PAYMENT_INFO.get(deps.storage, deal.payer())
+    } else {
+        None
+    };
+
+    Ok(QueryAnswer::DealDetail {
+        deal,
+        payment_info,
+        payer_payment_info,
     })
 }
 
-pub fn revenue(deps: Deps) -> StdResult<QueryAnswer> {
-    Ok(QueryAnswer::Revenue {
-        revenue: TOKEN_REVENUE.load(deps.storage)?,
+pub fn dispute_evidence(deps: Deps, user: Addr, deal_id: Uint128) -> StdResult<QueryAnswer> {
+    let config = CONFIG.load(deps.storage)?;
+    let curr_mods = MODERATORS.load(deps.storage)?;
+
+    // the evidence stays readable after the dispute is settled
+    let deal = DEALS.get(deps.storage, &deal_id)
+        .ok_or_else(|| StdError::generic_err("No deal is found"))?;
+
+    let roles = deal.roles_of(&user, &config.admins, &curr_mods);
+
+    if !roles.iter().any(|role| role != &DealRole::Admin) {
+        return Err(StdError::generic_err("Unauthorized"));
+    }
+
+    Ok(QueryAnswer::DisputeEvidence {
+        evidence: DEAL_EVIDENCE.get(deps.storage, &deal_id).unwrap_or_default(),
     })
 }
 
+pub fn revenue(deps: Deps, token: Option<String>) -> StdResult<QueryAnswer> {
+    let revenue = match token {
+        Some(token) => {
+            let token_addr = deps.api.addr_validate(&token)?;
+            TOKEN_REVENUE.get(deps.storage, &token_addr).into_iter().collect()
+        },
+        None => TOKEN_REVENUE.iter(deps.storage)?
+            .map(|x| x.map(|(_, revenue)| revenue))
+            .collect::<StdResult<Vec<_>>>()?,
+    };
+
+    Ok(QueryAnswer::Revenue { revenue })
+}
+
 pub fn moderators(deps: Deps) -> StdResult<QueryAnswer> {
     Ok(QueryAnswer::Moderators {
         mods: MODERATORS.load(deps.storage)?,
     })
-}
\ No newline at end of file
+}
+
+pub fn deal_tokens(deps: Deps) -> StdResult<QueryAnswer> {
+    let tokens = DEAL_TOKENS.iter(deps.storage)?
+        .map(|x| x.map(|(_, token)| token))
+        .collect::<StdResult<Vec<_>>>()?;
+
+    Ok(QueryAnswer::DealTokens { tokens })
+}
+
+pub fn currencies(deps: Deps) -> StdResult<QueryAnswer> {
+    let currencies = CURRENCIES.iter(deps.storage)?
+        .map(|x| x.map(|(_, currency)| currency))
+        .collect::<StdResult<Vec<_>>>()?;
+
+    Ok(QueryAnswer::Currencies { currencies })
+}
+
+pub fn status(deps: Deps) -> StdResult<QueryAnswer> {
+    let status = CONTRACT_STATUS.load(deps.storage)?;
+
+    Ok(QueryAnswer::Status {
+        status: status.status,
+        since: status.since,
+    })
+}
+
+pub fn solvency(deps: Deps, env: Env) -> StdResult<QueryAnswer> {
+    let mut tokens: Vec<TokenSolvency> = Vec::new();
+
+    for item in ESCROW_TOTALS.iter(deps.storage)? {
+        let (token_addr, escrow) = item?;
+
+        let balance = TOKEN_VIEWING_KEYS.get(deps.storage, &token_addr).and_then(|key| {
+            balance_query(
+                deps.querier,
+                env.contract.address.to_string(),
+                key,
+                RESPONSE_BLOCK_SIZE,
+                escrow.token.code_hash.clone(),
+                token_addr.to_string(),
+            ).ok()
+        }).map(|x| x.amount);
+
+        tokens.push(TokenSolvency {
+            token: token_addr,
+            escrow: escrow.amount,
+            balance,
+            solvent: balance.map(|x| x >= escrow.amount),
+        });
+    }
+
+    Ok(QueryAnswer::Solvency { tokens })
+}
+
+// One page of an id set in the requested order, starting after `start_after`.
+// Ids are handed out sequentially up to `max_id`, so the id range is walked with contains()
+// instead of loading the set, and a page checks at most MAX_SCAN ids however large the set grows.
+// `load` returns the item of an id in the set, or None to skip it.
+// Also returns the start_after of the next page, None once the range is done.
+fn scan_ids<T>(
+    storage: &dyn Storage,
+    ids: &Keyset<Uint128, Json>,
+    max_id: Uint128,
+    start_after: Option<Uint128>,
+    limit: Option<u32>,
+    order: Option<SortOrder>,
+    mut load: impl FnMut(Uint128) -> Option<T>,
+) -> (Vec<T>, Option<Uint128>) {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let max_id = max_id.u128();
+
+    let range: Box<dyn Iterator<Item = u128>> = match order.unwrap_or(SortOrder::Ascending) {
+        SortOrder::Ascending => {
+            let from = start_after.map_or(1, |x| x.u128().saturating_add(1));
+            Box::new(from..=max_id)
+        },
+        SortOrder::Descending => {
+            let to = start_after.map_or(max_id, |x| x.u128().saturating_sub(1).min(max_id));
+            Box::new((1..=to).rev())
+        },
+    };
+
+    let mut items: Vec<T> = Vec::new();
+    let mut last_id: Option<Uint128> = None;
+
+    for (id, scanned) in range.zip(0..) {
+        // more ids may follow
+        if items.len() >= limit || scanned >= MAX_SCAN {
+            return (items, last_id);
+        }
+
+        let id = Uint128::new(id);
+        last_id = Some(id);
+
+        if ids.contains(storage, &id) {
+            if let Some(item) = load(id) {
+                items.push(item);
+            }
+        }
+    }
+
+    (items, None)
+}
+
+// `party` restricts the page to the deals where that address is the customer or dealer
+fn page_deals(
+    storage: &dyn Storage,
+    ids: &Keyset<Uint128, Json>,
+    party: Option<&Addr>,
+    filter: Option<DealFilter>,
+    start_after: Option<Uint128>,
+    limit: Option<u32>,
+    order: Option<SortOrder>,
+) -> StdResult<(Vec<Deal>, Option<Uint128>)> {
+    let filter = filter.unwrap_or_default();
+    let max_id = MAX_DEAL_ID.load(storage)?;
+
+    Ok(scan_ids(storage, ids, max_id, start_after, limit, order, |deal_id| {
+        DEALS.get(storage, &deal_id).filter(|deal| {
+            let is_party = party.map_or(true, |x| &deal.customer == x || &deal.dealer == x);
+            is_party && deal_matches(deal, &filter)
+        })
+    }))
+}
+
+fn post_matches(post: &Post, filter: &PostFilter, now: Uint128) -> bool {
+    if !filter.include_expired.unwrap_or(false) && post.is_expired(now) {
+        return false;
+    }
+
+    filter.deal_token.as_ref().map_or(true, |x| post.deal_token.address.as_str() == x)
+        && filter.is_dealer_buy.map_or(true, |x| post.is_dealer_buy == x)
+        && filter.settle_currency.as_ref().map_or(true, |x| post.settle_currency == normalize_currency(x))
+        && filter.min_price.map_or(true, |x| post.settle_price >= x)
+        && filter.max_price.map_or(true, |x| post.settle_price <= x)
+        && filter.state.as_ref().map_or(true, |x| &post.state == x)
+}
+
+fn deal_matches(deal: &Deal, filter: &DealFilter) -> bool {
+    filter.deal_token.as_ref().map_or(true, |x| deal.deal_token.address.as_str() == x)
+        && filter.is_dealer_buy.map_or(true, |x| deal.is_dealer_buy == x)
+        && filter.settle_currency.as_ref().map_or(true, |x| deal.settle_currency == normalize_currency(x))
+        && filter.min_price.map_or(true, |x| deal.settle_price >= x)
+        && filter.max_price.map_or(true, |x| deal.settle_price <= x)
+        && filter.state.as_ref().map_or(true, |x| &deal.state == x)
+}
+
+#[cfg(test)]
+mod tests {
+    use cosmwasm_std::testing::mock_dependencies;
+    use cosmwasm_std::Storage;
+
+    use super::*;
+    use crate::state::testing::{mock_config, mock_deal, save_mock_state};
+
+    fn payment_info(owner: &str) -> PaymentInfo {
+        PaymentInfo {
+            method: "bank".to_string(),
+            detail: format!("account of {}", owner),
+        }
+    }
+
+    // a dealer sell deal, the dealer is paid by the customer
+    fn setup(storage: &mut dyn Storage) {
+        save_mock_state(storage, &mock_config(), &mock_deal());
+
+        for user in ["customer", "dealer"] {
+            USER_PAYMENT_INFO.insert(storage, &Addr::unchecked(user), &payment_info(user)).unwrap();
+        }
+    }
+
+    fn detail(deps: Deps, user: &str) -> StdResult<(PaymentInfo, Option<PaymentInfo>)> {
+        match deal_detail(deps, Addr::unchecked(user), Uint128::new(1))? {
+            QueryAnswer::DealDetail { payment_info, payer_payment_info, .. } => Ok((payment_info, payer_payment_info)),
+            _ => panic!("unexpected answer"),
+        }
+    }
+
+    #[test]
+    fn deal_detail_customer_sees_payee_only() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut().storage);
+
+        let (payee, payer) = detail(deps.as_ref(), "customer").unwrap();
+        assert_eq!(payee, payment_info("dealer"));
+        assert_eq!(payer, None);
+    }
+
+    #[test]
+    fn deal_detail_dealer_sees_payee_only() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut().storage);
+
+        let (payee, payer) = detail(deps.as_ref(), "dealer").unwrap();
+        assert_eq!(payee, payment_info("dealer"));
+        assert_eq!(payer, None);
+    }
+
+    #[test]
+    fn deal_detail_moderator_sees_both_parties() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut().storage);
+
+        let (payee, payer) = detail(deps.as_ref(), "moderator").unwrap();
+        assert_eq!(payee, payment_info("dealer"));
+        assert_eq!(payer, Some(payment_info("customer")));
+    }
+
+    #[test]
+    fn deal_detail_admin_sees_both_parties() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut().storage);
+
+        let (payee, payer) = detail(deps.as_ref(), "admin").unwrap();
+        assert_eq!(payee, payment_info("dealer"));
+        assert_eq!(payer, Some(payment_info("customer")));
+    }
+
+    #[test]
+    fn deal_detail_unrelated_user_is_rejected() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut().storage);
+
+        let err = detail(deps.as_ref(), "stranger").unwrap_err();
+        assert_eq!(err, StdError::generic_err("Unauthorized"));
+    }
+}
@@ -1,37 +1,101 @@
 use cosmwasm_schema::cw_serde;
 
-use cosmwasm_std::{Addr, Uint128};
+use cosmwasm_std::{Addr, StdError, StdResult, Storage, Uint128};
 
-use secret_toolkit::storage::{Item, Keymap};
+use secret_toolkit::storage::{Item, Keymap, Keyset};
 use secret_toolkit::serialization::Json;
 
-use crate::asset::{Contract, DealState, PostState};
+use crate::asset::{Contract, ContractError, ContractStatus, DealCancelPolicy, DealLimits, DealRole, DealState, DisputeDecision, DisputeTimeoutOutcome, EscrowOwner, PostState};
+
+// storage prefix of the permits revoked through RevokePermit
+pub const PREFIX_REVOKED_PERMITS: &str = "revoked_permits";
 
 // single value store
 pub const CONFIG: Item<Config, Json> = Item::new(b"config");
+pub const CONTRACT_INFO: Item<ContractInfo, Json> = Item::new(b"contract_info");
 pub const MAX_DEAL_ID: Item<Uint128> = Item::new(b"max_deal_id");
 pub const MAX_POST_ID: Item<Uint128> = Item::new(b"max_post_id");
-pub const TOKEN_REVENUE: Item<Uint128> = Item::new(b"token_revenue");
+pub const CONTRACT_STATUS: Item<StatusInfo, Json> = Item::new(b"contract_status");
+pub const MODERATOR_CURSOR: Item<u32> = Item::new(b"moderator_cursor");  // index of the next moderator to assign a dispute to
 
 // vector value store (a list of items)
-pub const PAST_DEALS: Item<Vec<Deal>, Json> = Item::new(b"deals");
-pub const ACTIVE_POSTS: Item<Vec<Post>, Json> = Item::new(b"active_posts");
-pub const ACTIVE_DEALS: Item<Vec<Deal>, Json> = Item::new(b"active_deals");
 pub const MODERATORS: Item<Vec<Addr>, Json> = Item::new(b"moderators");
 
+// keyed value store (record by id, lifecycle tracked by the id sets)
+pub const POSTS: Keymap<Uint128, Post, Json> = Keymap::new(b"post_store");
+pub const DEALS: Keymap<Uint128, Deal, Json> = Keymap::new(b"deal_store");
+pub const ACTIVE_POST_IDS: Keyset<Uint128, Json> = Keyset::new(b"active_post_ids");
+pub const ACTIVE_DEAL_IDS: Keyset<Uint128, Json> = Keyset::new(b"active_deal_ids");
+pub const PAST_DEAL_IDS: Keyset<Uint128, Json> = Keyset::new(b"past_deal_ids");
+
 // map value store (per user usually)
 pub const USER_PAYMENT_INFO: Keymap<Addr, PaymentInfo, Json> = Keymap::new(b"user_payment_info");
-pub const USER_COOL_DOWN: Keymap<Addr, Uint128, Json> = Keymap::new(b"user_cool_down");
+pub const DEAL_TOKENS: Keymap<Addr, DealToken, Json> = Keymap::new(b"deal_tokens");
+pub const CURRENCIES: Keymap<String, Currency, Json> = Keymap::new(b"currencies");
+pub const TOKEN_REVENUE: Keymap<Addr, TokenRevenue, Json> = Keymap::new(b"revenue_by_token");
+pub const ESCROW: Keymap<(Addr, EscrowOwner), Uint128, Json> = Keymap::new(b"escrow");
+pub const ESCROW_TOTALS: Keymap<Addr, TokenEscrow, Json> = Keymap::new(b"escrow_totals");
+pub const TOKEN_VIEWING_KEYS: Keymap<Addr, String, Json> = Keymap::new(b"token_viewing_keys");
+pub const USER_COOL_DOWN: Keymap<Addr, Uint128, Json> = Keymap::new(b"user_cool_down");  // time until a customer can enter deals again
+pub const ACTIVE_DEAL_COUNTS: Keymap<Addr, ActiveDealCount, Json> = Keymap::new(b"active_deal_counts");
+pub const DEAL_EVIDENCE: Keymap<Uint128, Vec<Evidence>, Json> = Keymap::new(b"deal_evidence");
+
+#[cw_serde]
+pub struct ContractInfo {
+    pub contract: String,
+    pub version: String,
+}
+
+#[cw_serde]
+pub struct StatusInfo {
+    pub status: ContractStatus,
+    pub since: Uint128,  // time the status was set
+}
 
 #[cw_serde]
 pub struct Config {
     pub admins: Vec<Addr>,
     pub deal_commission: Uint128,  // in bps, 1 bps = 0.01% commision
-    pub deal_token_a: Contract,
-    pub deal_token_b: Contract,
-    pub deal_token_c: Contract,
     pub query_auth: Contract,
     pub governance: Option<Contract>,
+    pub treasury: Option<Addr>,  // receiver of the commission, the calling admin if None
+    pub deal_cancel_policy: DealCancelPolicy,
+    pub deal_limits: DealLimits,
+    pub dispute_quorum: u32,  // moderator votes needed to settle a dispute
+    pub dispute_timeout_outcome: DisputeTimeoutOutcome,
+}
+
+// snip-20 token supported for trading, registered by admin / governance
+#[cw_serde]
+pub struct DealToken {
+    pub contract: Contract,
+    pub symbol: String,
+    pub decimals: u8,
+    pub min_post_amount: Uint128,  // min size of a post in this token
+    pub max_post_amount: Option<Uint128>,  // max size of a post in this token, no cap if None
+}
+
+// fiat currency that posts can settle in, registered by admin / governance
+#[cw_serde]
+#[derive(Eq)]
+pub struct Currency {
+    pub code: String,  // ISO-4217 code, e.g. USD
+    pub decimals: u8,  // display decimals, e.g. 2 for USD
+    pub enabled: bool,  // new posts only accept enabled currencies
+}
+
+// commission collected in a token and not yet withdrawn
+#[cw_serde]
+pub struct TokenRevenue {
+    pub token: Contract,
+    pub amount: Uint128,
+}
+
+// total escrow of a token, the sum of the ESCROW balances in that token
+#[cw_serde]
+pub struct TokenEscrow {
+    pub token: Contract,
+    pub amount: Uint128,
 }
 
 #[cw_serde]
@@ -41,6 +105,7 @@ pub struct Post {
     pub deal_token: Contract,
     pub amount: Uint128, // number of crypto, also this is the remaining of the post amount
     pub min_amount: Uint128, // min amount allowed to init the deal
+    pub max_amount: Option<Uint128>, // max amount of a single deal, up to the remaining amount if None
     pub settle_currency: String,
     pub settle_price: Uint128,
     pub dealer_deposit: bool,
@@ -49,6 +114,13 @@ pub struct Post {
     pub expiry: Uint128,
 }
 
+impl Post {
+    // an expired post cannot be entered or deposited to, until the dealer renews it
+    pub fn is_expired(&self, now: Uint128) -> bool {
+        self.expiry <= now
+    }
+}
+
 #[cw_serde]
 pub struct Deal {
     pub deal_id: Uint128,
@@ -65,10 +137,279 @@ pub struct Deal {
     pub state: DealState,
     pub resolver: Option<Addr>,
     pub expiry: Option<Uint128>,
+    pub close_reason: Option<String>,  // set when an admin force-closes the deal
+    pub votes: Vec<DisputeVote>,  // moderator votes while in dispute
+    pub assigned_moderator: Option<Addr>,  // moderator expected to vote next on the dispute
+    pub assignment_expiry: Option<Uint128>,  // the dispute can be reassigned after this time
+    pub escalated: bool,  // dispute expired and was handed to the admins
+}
+
+#[cw_serde]
+pub struct DisputeVote {
+    pub moderator: Addr,
+    pub decision: DisputeDecision,
+    pub time: Uint128,
+}
+
+impl Deal {
+    pub fn roles_of(&self, user: &Addr, admins: &[Addr], moderators: &[Addr]) -> Vec<DealRole> {
+        let mut roles: Vec<DealRole> = Vec::new();
+
+        if admins.contains(user) {
+            roles.push(DealRole::Admin);
+        }
+        if moderators.contains(user) {
+            roles.push(DealRole::Moderator);
+        }
+        if &self.customer == user {
+            roles.push(DealRole::Customer);
+        }
+        if &self.dealer == user {
+            roles.push(DealRole::Dealer);
+        }
+
+        roles
+    }
+
+    // the party receiving the bank transfer, i.e. the one selling crypto
+    pub fn payee(&self) -> &Addr {
+        if self.is_dealer_buy { &self.customer } else { &self.dealer }
+    }
+
+    pub fn payer(&self) -> &Addr {
+        if self.is_dealer_buy { &self.dealer } else { &self.customer }
+    }
+
+    // the payee deposits the crypto, the deposit is held by the deal once made
+    pub fn is_deposited(&self) -> bool {
+        self.dealer_deposit || self.customer_deposit
+    }
+}
+
+// evidence submitted by a party of a disputed deal, the file itself is kept off-chain
+#[cw_serde]
+pub struct Evidence {
+    pub submitter: Addr,
+    pub content_hash: String,
+    pub note: String,
+    pub time: Uint128,
+}
+
+// number of active deals of a user, kept up to date by save_active_deal / archive_deal
+#[cw_serde]
+#[derive(Default)]
+pub struct ActiveDealCount {
+    pub as_customer: u32,
+    pub as_dealer: u32,
 }
 
 #[cw_serde]
 pub struct PaymentInfo {
     pub method: String,
     pub detail: String,
-}
\ No newline at end of file
+}
+
+pub fn load_active_post(storage: &dyn Storage, post_id: &Uint128) -> Result<Post, ContractError> {
+    if !ACTIVE_POST_IDS.contains(storage, post_id) {
+        return Err(ContractError::NoMatchingPost(*post_id));
+    }
+
+    POSTS.get(storage, post_id).ok_or(ContractError::NoMatchingPost(*post_id))
+}
+
+pub fn save_active_post(storage: &mut dyn Storage, post: &Post) -> StdResult<()> {
+    POSTS.insert(storage, &post.post_id, post)?;
+    ACTIVE_POST_IDS.insert(storage, &post.post_id)?;
+    Ok(())
+}
+
+// a removed post leaves no history, same as before
+pub fn remove_post(storage: &mut dyn Storage, post_id: &Uint128) -> StdResult<()> {
+    if ACTIVE_POST_IDS.contains(storage, post_id) {
+        ACTIVE_POST_IDS.remove(storage, post_id)?;
+    }
+    if POSTS.contains(storage, post_id) {
+        POSTS.remove(storage, post_id)?;
+    }
+    Ok(())
+}
+
+pub fn load_active_deal(storage: &dyn Storage, deal_id: &Uint128) -> Result<Deal, ContractError> {
+    if !ACTIVE_DEAL_IDS.contains(storage, deal_id) {
+        return Err(ContractError::NoMatchingDeal(*deal_id));
+    }
+
+    DEALS.get(storage, deal_id).ok_or(ContractError::NoMatchingDeal(*deal_id))
+}
+
+pub fn save_active_deal(storage: &mut dyn Storage, deal: &Deal) -> StdResult<()> {
+    if !ACTIVE_DEAL_IDS.contains(storage, &deal.deal_id) {
+        ACTIVE_DEAL_IDS.insert(storage, &deal.deal_id)?;
+        count_active_deal(storage, deal, true)?;
+    }
+    DEALS.insert(storage, &deal.deal_id, deal)?;
+    Ok(())
+}
+
+// move a finished deal from the active set into the past deals
+pub fn archive_deal(storage: &mut dyn Storage, deal: &Deal) -> StdResult<()> {
+    DEALS.insert(storage, &deal.deal_id, deal)?;
+    if ACTIVE_DEAL_IDS.contains(storage, &deal.deal_id) {
+        ACTIVE_DEAL_IDS.remove(storage, &deal.deal_id)?;
+        count_active_deal(storage, deal, false)?;
+    }
+    PAST_DEAL_IDS.insert(storage, &deal.deal_id)?;
+    Ok(())
+}
+
+pub fn load_active_deal_count(storage: &dyn Storage, user: &Addr) -> ActiveDealCount {
+    ACTIVE_DEAL_COUNTS.get(storage, user).unwrap_or_default()
+}
+
+fn count_active_deal(storage: &mut dyn Storage, deal: &Deal, opened: bool) -> StdResult<()> {
+    let mut customer = load_active_deal_count(storage, &deal.customer);
+    customer.as_customer = if opened { customer.as_customer + 1 } else { customer.as_customer.saturating_sub(1) };
+    ACTIVE_DEAL_COUNTS.insert(storage, &deal.customer, &customer)?;
+
+    let mut dealer = load_active_deal_count(storage, &deal.dealer);
+    dealer.as_dealer = if opened { dealer.as_dealer + 1 } else { dealer.as_dealer.saturating_sub(1) };
+    ACTIVE_DEAL_COUNTS.insert(storage, &deal.dealer, &dealer)
+}
+
+pub fn add_revenue(storage: &mut dyn Storage, token: &Contract, amount: Uint128) -> StdResult<()> {
+    let mut revenue = TOKEN_REVENUE.get(storage, &token.address).unwrap_or(TokenRevenue {
+        token: token.clone(),
+        amount: Uint128::zero(),
+    });
+    revenue.amount += amount;
+    TOKEN_REVENUE.insert(storage, &token.address, &revenue)
+}
+
+// The escrow ledger records who the tokens held by the contract belong to.
+// Every deposit is credited and every transfer out of the contract is debited.
+pub fn escrow_credit(storage: &mut dyn Storage, token: &Contract, owner: EscrowOwner, amount: Uint128) -> StdResult<()> {
+    let key = (token.address.clone(), owner);
+    let balance = ESCROW.get(storage, &key).unwrap_or_default();
+    ESCROW.insert(storage, &key, &(balance + amount))?;
+
+    let mut total = ESCROW_TOTALS.get(storage, &token.address).unwrap_or(TokenEscrow {
+        token: token.clone(),
+        amount: Uint128::zero(),
+    });
+    total.amount += amount;
+    ESCROW_TOTALS.insert(storage, &token.address, &total)
+}
+
+pub fn escrow_debit(storage: &mut dyn Storage, token: &Contract, owner: EscrowOwner, amount: Uint128) -> Result<(), ContractError> {
+    // e.g. a zero commission, the balance may already be gone
+    if amount.is_zero() {
+        return Ok(());
+    }
+
+    let key = (token.address.clone(), owner);
+    let balance = ESCROW.get(storage, &key).unwrap_or_default();
+    if balance < amount {
+        return Err(ContractError::InsufficientEscrow(balance, amount));
+    }
+
+    if balance == amount {
+        ESCROW.remove(storage, &key)?;
+    } else {
+        ESCROW.insert(storage, &key, &(balance - amount))?;
+    }
+
+    let mut total = ESCROW_TOTALS.get(storage, &token.address)
+        .ok_or(ContractError::InsufficientEscrow(Uint128::zero(), amount))?;
+    total.amount -= amount;
+    ESCROW_TOTALS.insert(storage, &token.address, &total)?;
+
+    Ok(())
+}
+
+// move a balance between two owners, the token stays in the contract
+pub fn escrow_move(storage: &mut dyn Storage, token: &Contract, from: EscrowOwner, to: EscrowOwner, amount: Uint128) -> Result<(), ContractError> {
+    escrow_debit(storage, token, from, amount)?;
+    escrow_credit(storage, token, to, amount)?;
+    Ok(())
+}
+
+pub fn load_active_posts(storage: &dyn Storage) -> StdResult<Vec<Post>> {
+    ACTIVE_POST_IDS.iter(storage)?
+        .map(|post_id| {
+            let post_id = post_id?;
+            POSTS.get(storage, &post_id)
+                .ok_or_else(|| StdError::not_found(format!("Post {}", post_id)))
+        })
+        .collect()
+}
+
+pub fn load_active_deals(storage: &dyn Storage) -> StdResult<Vec<Deal>> {
+    load_deals(storage, &ACTIVE_DEAL_IDS)
+}
+
+pub fn load_past_deals(storage: &dyn Storage) -> StdResult<Vec<Deal>> {
+    load_deals(storage, &PAST_DEAL_IDS)
+}
+
+fn load_deals(storage: &dyn Storage, ids: &Keyset<Uint128, Json>) -> StdResult<Vec<Deal>> {
+    ids.iter(storage)?
+        .map(|deal_id| {
+            let deal_id = deal_id?;
+            DEALS.get(storage, &deal_id)
+                .ok_or_else(|| StdError::not_found(format!("Deal {}", deal_id)))
+        })
+        .collect()
+}
+
+// fixtures shared by the unit tests
+#[cfg(test)]
+pub mod testing {
+    use super::*;
+
+    // one admin, no commission, every other setting at its default
+    pub fn mock_config() -> Config {
+        Config {
+            admins: vec![Addr::unchecked("admin")],
+            deal_commission: Uint128::zero(),
+            query_auth: Contract::new(&Addr::unchecked("query_auth"), &String::new()),
+            governance: None,
+            treasury: None,
+            deal_cancel_policy: DealCancelPolicy::default(),
+            deal_limits: DealLimits::default(),
+            dispute_quorum: 1,
+            dispute_timeout_outcome: DisputeTimeoutOutcome::default(),
+        }
+    }
+
+    // deal 1, a dealer sell deal deposited by the dealer and waiting for the bank transfer of the customer
+    pub fn mock_deal() -> Deal {
+        Deal {
+            deal_id: Uint128::new(1),
+            post_id: Uint128::new(1),
+            is_dealer_buy: false,
+            deal_token: Contract::new(&Addr::unchecked("token"), &String::new()),
+            amount: Uint128::new(1_000_000),
+            settle_currency: "USD".to_string(),
+            settle_price: Uint128::new(1_000_000),
+            dealer_deposit: true,
+            customer_deposit: false,
+            dealer: Addr::unchecked("dealer"),
+            customer: Addr::unchecked("customer"),
+            state: DealState::PendCustomerBankTransfer,
+            resolver: None,
+            expiry: None,
+            close_reason: None,
+            votes: vec![],
+            assigned_moderator: None,
+            assignment_expiry: None,
+            escalated: false,
+        }
+    }
+
+    // save the config, a single moderator and the deal as active
+    pub fn save_mock_state(storage: &mut dyn Storage, config: &Config, deal: &Deal) {
+        CONFIG.save(storage, config).unwrap();
+        MODERATORS.save(storage, &vec![Addr::unchecked("moderator")]).unwrap();
+        save_active_deal(storage, deal).unwrap();
+    }
+}
@@ -0,0 +1,86 @@
+use cosmwasm_std::{Addr, Storage, Uint128};
+
+use crate::asset::{ContractError, MAX_EVIDENCE_HASH_LENGTH, MAX_EVIDENCE_NOTE_LENGTH};
+use crate::state::{Currency, CURRENCIES};
+
+// Input checks of the post and deal messages
+
+pub fn validate_post_amounts(amount: Uint128, min_amount: Uint128, max_amount: Option<Uint128>) -> Result<(), ContractError> {
+    if amount.is_zero() {
+        return Err(ContractError::ZeroAmount {});
+    }
+
+    if min_amount > amount {
+        return Err(ContractError::MinAmountMoreThanAmount {});
+    }
+
+    if let Some(max_amount) = max_amount {
+        if max_amount < min_amount || max_amount > amount {
+            return Err(ContractError::InvalidMaxAmount {});
+        }
+    }
+
+    Ok(())
+}
+
+pub fn validate_settle_price(settle_price: Uint128) -> Result<(), ContractError> {
+    if settle_price.is_zero() {
+        return Err(ContractError::ZeroSettlePrice {});
+    }
+
+    Ok(())
+}
+
+// currency codes are stored in upper case, so "usd" and "USD" are the same market
+pub fn normalize_currency(currency: &str) -> String {
+    currency.trim().to_ascii_uppercase()
+}
+
+// ISO-4217 alphabetic code, e.g. USD
+pub fn validate_currency_code(currency: &str) -> Result<(), ContractError> {
+    if currency.len() != 3 || !currency.chars().all(|c| c.is_ascii_uppercase()) {
+        return Err(ContractError::InvalidCurrencyCode(currency.to_string()));
+    }
+
+    Ok(())
+}
+
+// registry entry with a normalized code
+pub fn validate_currency(currency: Currency) -> Result<Currency, ContractError> {
+    let code = normalize_currency(&currency.code);
+    validate_currency_code(&code)?;
+
+    Ok(Currency { code, ..currency })
+}
+
+// the currency has to be registered and enabled
+pub fn validate_settle_currency(storage: &dyn Storage, currency: &str) -> Result<(), ContractError> {
+    match CURRENCIES.get(storage, &currency.to_string()) {
+        Some(registered) if registered.enabled => Ok(()),
+        _ => Err(ContractError::UnsupportedCurrency(currency.to_string())),
+    }
+}
+
+pub fn validate_deal_amount(amount: Uint128) -> Result<(), ContractError> {
+    if amount.is_zero() {
+        return Err(ContractError::ZeroAmount {});
+    }
+
+    Ok(())
+}
+
+pub fn validate_not_self_deal(dealer: &Addr, customer: &Addr) -> Result<(), ContractError> {
+    if dealer == customer {
+        return Err(ContractError::SelfDealing {});
+    }
+
+    Ok(())
+}
+
+pub fn validate_evidence(content_hash: &str, note: &str) -> Result<(), ContractError> {
+    if content_hash.is_empty() || content_hash.len() > MAX_EVIDENCE_HASH_LENGTH || note.len() > MAX_EVIDENCE_NOTE_LENGTH {
+        return Err(ContractError::InvalidEvidence(MAX_EVIDENCE_HASH_LENGTH, MAX_EVIDENCE_NOTE_LENGTH));
+    }
+
+    Ok(())
+}